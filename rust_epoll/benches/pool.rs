@@ -0,0 +1,209 @@
+use std::{
+    collections::VecDeque,
+    hint::black_box,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rust_epoll::{
+    deque::{Injector, Steal, Stealer, Worker},
+    pool::ThreadPool,
+};
+
+const TASKS: usize = 1_000_000;
+const QUEUE: usize = 64;
+const RUNS: usize = 5;
+
+trait Queues: Send + Sync + 'static {
+    type Local: Send + 'static;
+    fn new(workers: usize) -> (Self, Vec<Self::Local>)
+    where
+        Self: Sized;
+    fn push(&self, task: usize);
+    fn find(&self, id: usize, local: &Self::Local, tick: usize) -> Option<usize>;
+}
+
+//What the pool used before, a locked global queue and locked ring buffers stolen from the owner's end
+struct Locked {
+    global: Mutex<VecDeque<usize>>,
+    locals: Vec<Mutex<VecDeque<usize>>>,
+}
+impl Queues for Locked {
+    type Local = ();
+    fn new(workers: usize) -> (Self, Vec<()>) {
+        let locals = (0..workers)
+            .map(|_| Mutex::new(VecDeque::with_capacity(QUEUE)))
+            .collect();
+        let queues = Self {
+            global: Mutex::new(VecDeque::new()),
+            locals,
+        };
+        (queues, vec![(); workers])
+    }
+    fn push(&self, task: usize) {
+        self.global.lock().unwrap().push_back(task);
+    }
+    fn find(&self, id: usize, _: &(), tick: usize) -> Option<usize> {
+        let local = &self.locals[id];
+        if tick.is_multiple_of(61) {
+            let mut global = self.global.lock().unwrap();
+            let mut lq = local.lock().unwrap();
+            if lq.len() < QUEUE
+                && let Some(task) = global.pop_front()
+            {
+                lq.push_back(task);
+            }
+        }
+        if let Some(task) = local.lock().unwrap().pop_front() {
+            return Some(task);
+        }
+        for t_id in 0..self.locals.len() {
+            if t_id == id {
+                continue;
+            }
+            let (first, second) = if t_id < id {
+                (&self.locals[t_id], local)
+            } else {
+                (local, &self.locals[t_id])
+            };
+            let first = first.lock().unwrap();
+            let second = second.lock().unwrap();
+            let (mut tq, mut lq) = if t_id < id {
+                (first, second)
+            } else {
+                (second, first)
+            };
+            if lq.len() < QUEUE
+                && let Some(task) = tq.pop_back()
+            {
+                lq.push_back(task);
+            }
+        }
+        None
+    }
+}
+
+struct LockFree {
+    injector: Injector<usize>,
+    stealers: Vec<Stealer<usize>>,
+}
+impl Queues for LockFree {
+    type Local = Worker<usize>;
+    fn new(workers: usize) -> (Self, Vec<Worker<usize>>) {
+        let locals: Vec<Worker<usize>> = (0..workers).map(|_| Worker::new(QUEUE)).collect();
+        let queues = Self {
+            injector: Injector::new(),
+            stealers: locals.iter().map(Worker::stealer).collect(),
+        };
+        (queues, locals)
+    }
+    fn push(&self, task: usize) {
+        self.injector.push(task);
+    }
+    fn find(&self, id: usize, local: &Worker<usize>, _: usize) -> Option<usize> {
+        if let Some(task) = local.pop() {
+            return Some(task);
+        }
+        if let Some(task) = self.injector.pop() {
+            while local.len() < QUEUE / 2
+                && let Some(next) = self.injector.pop()
+            {
+                if let Err(next) = local.push(next) {
+                    self.injector.push(next);
+                    break;
+                }
+            }
+            return Some(task);
+        }
+        let workers = self.stealers.len();
+        (1..workers).find_map(|offset| {
+            loop {
+                match self.stealers[(id + offset) % workers].steal() {
+                    Steal::Success(task) => break Some(task),
+                    Steal::Empty => break None,
+                    Steal::Retry => continue,
+                }
+            }
+        })
+    }
+}
+
+fn work(task: usize) -> usize {
+    (0..32).fold(task, |acc, i| {
+        black_box(acc.wrapping_mul(31).wrapping_add(i))
+    })
+}
+
+//One producer feeds the queues while every worker spins on find
+fn queues<Q: Queues>(workers: usize) -> Duration {
+    let (queues, locals) = Q::new(workers);
+    let queues = Arc::new(queues);
+    let done = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let threads: Vec<_> = locals
+        .into_iter()
+        .enumerate()
+        .map(|(id, local)| {
+            let queues = Arc::clone(&queues);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut tick = 0;
+                while done.load(Ordering::Relaxed) < TASKS {
+                    tick += 1;
+                    if let Some(task) = queues.find(id, &local, tick) {
+                        black_box(work(task));
+                        done.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        })
+        .collect();
+    for task in 0..TASKS {
+        queues.push(task);
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn pool(workers: usize) -> Duration {
+    let pool = ThreadPool::builder()
+        .workers(workers)
+        .queue_capacity(QUEUE)
+        .build();
+    pool.dispatch();
+    let start = Instant::now();
+    for task in 0..TASKS {
+        pool.enqueue(Box::new(move |_| {
+            black_box(work(task));
+            Ok(())
+        }));
+    }
+    while pool.pending() > 0 {
+        thread::yield_now();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, workers: usize, bench: fn(usize) -> Duration) {
+    let best = (0..RUNS).map(|_| bench(workers)).min().unwrap();
+    println!(
+        "{name:<10} workers={workers:<3} best of {RUNS}: {:>8.2?} {:>6.2} Mtasks/s",
+        best,
+        TASKS as f64 / best.as_secs_f64() / 1e6
+    );
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    for workers in [2, 4, cores] {
+        report("locked", workers, queues::<Locked>);
+        report("lock-free", workers, queues::<LockFree>);
+        report("pool", workers, pool);
+    }
+}
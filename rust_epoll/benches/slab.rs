@@ -0,0 +1,114 @@
+use std::{
+    hint::black_box,
+    net::{TcpListener, TcpStream},
+    time::{Duration, Instant},
+};
+
+use rust_epoll::{
+    polller::{ConnectionState, Event, Poller},
+    slab::Slab,
+};
+
+const CHURN: usize = 100_000;
+const MEASURED: usize = 500;
+
+//What Poller and Telementry used before, the first free slot is found by scanning
+struct Linear<T>(Vec<Option<T>>);
+impl<T> Linear<T> {
+    fn insert(&mut self, value: T) -> usize {
+        if let Some(index) = self.0.iter().position(|slot| slot.is_none()) {
+            self.0[index] = Some(value);
+            index
+        } else {
+            self.0.push(Some(value));
+            self.0.len() - 1
+        }
+    }
+    fn remove(&mut self, index: usize) -> Option<T> {
+        self.0.get_mut(index)?.take()
+    }
+}
+
+//Small LCG so both structures see the same close order
+fn next(seed: &mut u64, bound: usize) -> usize {
+    *seed = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    usize::try_from(*seed >> 33).unwrap() % bound
+}
+
+//With live entries held, close a random one and open a new one
+fn churn_linear(live: usize) -> Duration {
+    let mut slots = Linear(Vec::new());
+    let mut keys: Vec<usize> = (0..live).map(|i| slots.insert(i)).collect();
+    let mut seed = 1;
+    let start = Instant::now();
+    for i in 0..CHURN {
+        let victim = next(&mut seed, live);
+        black_box(slots.remove(keys[victim]));
+        keys[victim] = slots.insert(i);
+    }
+    start.elapsed() / CHURN as u32
+}
+
+fn churn_slab(live: usize) -> Duration {
+    let mut slots = Slab::new();
+    let mut keys: Vec<usize> = (0..live).map(|i| slots.insert(i)).collect();
+    let mut seed = 1;
+    let start = Instant::now();
+    for i in 0..CHURN {
+        let victim = next(&mut seed, live);
+        black_box(slots.remove(keys[victim]));
+        keys[victim] = slots.insert(i);
+    }
+    start.elapsed() / CHURN as u32
+}
+
+//Opens live connections in batches and times how long the poller takes to accept the last ones
+fn accept(live: usize) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut poller = Poller::new(256).unwrap();
+    poller.add_listener(listener).unwrap();
+
+    let mut clients = Vec::with_capacity(live);
+    let mut accepted = 0;
+    let mut elapsed = Duration::ZERO;
+    while clients.len() < live {
+        let batch = (live - clients.len()).min(100);
+        for _ in 0..batch {
+            clients.push(TcpStream::connect(addr).unwrap());
+        }
+        let start = Instant::now();
+        let target = accepted + batch;
+        while accepted < target {
+            poller
+                .poll(100, |event| {
+                    if let Event::Connection(conn) = event
+                        && let ConnectionState::Opened = conn.state
+                    {
+                        accepted += 1;
+                    }
+                })
+                .unwrap();
+        }
+        if clients.len() > live - MEASURED {
+            elapsed += start.elapsed();
+        }
+    }
+    elapsed / MEASURED as u32
+}
+
+fn main() {
+    for live in [1_000, 10_000, 100_000] {
+        println!(
+            "churn  live={live:<7} linear {:>10.2?}/op  slab {:>8.2?}/op",
+            churn_linear(live),
+            churn_slab(live)
+        );
+    }
+    //Each connection costs two descriptors in this process
+    for live in [1_000, 4_000, 8_000] {
+        println!("accept live={live:<7} {:>8.2?}/connection", accept(live));
+    }
+}
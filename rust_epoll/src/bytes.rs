@@ -0,0 +1,133 @@
+use std::{
+    io::{Error, ErrorKind, Read},
+    ops::Deref,
+};
+
+const READ_CHUNK: usize = 4096;
+
+//Growable byte buffer that is filled at the back and consumed from the front
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BytesBuf {
+    data: Vec<u8>,
+    //Bytes before this have been consumed
+    start: usize,
+}
+impl BytesBuf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            data: Vec::with_capacity(capacity),
+            start: 0,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.data.len() - self.start
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[self.start..]
+    }
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.compact();
+        self.data.extend_from_slice(bytes);
+    }
+    //Drops the first count bytes
+    pub fn advance(&mut self, count: usize) {
+        assert!(count <= self.len(), "advanced past the end of the buffer");
+        self.start += count;
+        if self.start == self.data.len() {
+            self.clear();
+        }
+    }
+    //Removes and returns the first count bytes
+    pub fn split_to(&mut self, count: usize) -> Vec<u8> {
+        let front = self.as_slice()[..count].to_vec();
+        self.advance(count);
+        front
+    }
+    //Takes everything that has not been consumed
+    pub fn take(&mut self) -> Vec<u8> {
+        let mut data = std::mem::take(&mut self.data);
+        data.drain(..self.start);
+        self.start = 0;
+        data
+    }
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.start = 0;
+    }
+    //Reads until the source would block or limit bytes have been read, returns the bytes read
+    //and whether it reached EOF
+    pub fn read_from<R: Read>(
+        &mut self,
+        source: &mut R,
+        limit: usize,
+    ) -> Result<(usize, bool), Error> {
+        self.compact();
+        let mut total = 0;
+        while total < limit {
+            let filled = self.data.len();
+            self.data.resize(filled + READ_CHUNK.min(limit - total), 0);
+            let read = source.read(&mut self.data[filled..]);
+            self.data.truncate(filled + *read.as_ref().unwrap_or(&0));
+            match read {
+                Ok(0) => return Ok((total, true)),
+                Ok(size) => total += size,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok((total, false)),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok((total, false))
+    }
+    //Moves the unconsumed bytes to the front once they are less than what was consumed
+    fn compact(&mut self) {
+        if self.start > 0 && self.start >= self.len() {
+            self.data.drain(..self.start);
+            self.start = 0;
+        }
+    }
+}
+impl Deref for BytesBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+impl From<&[u8]> for BytesBuf {
+    fn from(bytes: &[u8]) -> Self {
+        Self {
+            data: bytes.to_vec(),
+            start: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bytes_buf_test() {
+        let mut buf = BytesBuf::new();
+        buf.extend_from_slice(b"hello world");
+        assert_eq!(buf.split_to(6), b"hello ");
+        assert_eq!(&buf[..], b"world");
+        buf.extend_from_slice(b"!");
+        println!("Buffer {:?}", buf);
+        assert_eq!(buf.start, 0, "Consumed bytes were not compacted");
+        assert_eq!(buf.take(), b"world!");
+        assert!(buf.is_empty());
+
+        let mut source: &[u8] = &[7; READ_CHUNK + 10];
+        let (read, eof) = buf.read_from(&mut source, 100).unwrap();
+        assert_eq!((read, eof), (100, false));
+        let (read, eof) = buf.read_from(&mut source, usize::MAX).unwrap();
+        assert_eq!((read, eof), (READ_CHUNK - 90, true));
+        assert_eq!(buf.len(), READ_CHUNK + 10);
+    }
+}
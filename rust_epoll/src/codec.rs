@@ -0,0 +1,108 @@
+use std::io::{Error, ErrorKind};
+
+use crate::bytes::BytesBuf;
+use crate::framer::{Framer, LengthPrefixed, Lines};
+
+//Turns bytes into requests and responses into bytes, used by AsyncListener::serve_codec
+pub trait Codec: Send + Sync + 'static {
+    type Req: Send;
+    type Resp: Send;
+    //Removes one request from the front of buf, None until enough bytes have arrived.
+    //An error closes the connection
+    fn decode(&self, buf: &mut BytesBuf) -> Result<Option<Self::Req>, Error>;
+    fn encode(&self, resp: Self::Resp, buf: &mut BytesBuf);
+    //A response returned for a decode error is sent before the connection closes,
+    //by default the error is passed on and nothing more is sent
+    fn reject(&self, err: Error) -> Result<Self::Resp, Error> {
+        Err(err)
+    }
+    //False closes the connection once resp is sent, requests after it are dropped
+    fn keep_open(&self, _resp: &Self::Resp) -> bool {
+        true
+    }
+}
+
+//UTF-8 lines, responses get a \n appended
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Line(pub Lines);
+impl Codec for Line {
+    type Req = String;
+    type Resp = String;
+    fn decode(&self, buf: &mut BytesBuf) -> Result<Option<String>, Error> {
+        let Some(line) = self.0.next_frame(buf)? else {
+            return Ok(None);
+        };
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+    fn encode(&self, resp: String, buf: &mut BytesBuf) {
+        buf.extend_from_slice(resp.as_bytes());
+        buf.extend_from_slice(b"\n");
+    }
+}
+
+//Big-endian u32 length before each message in both directions
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LengthPrefix(pub LengthPrefixed);
+impl Codec for LengthPrefix {
+    type Req = Vec<u8>;
+    type Resp = Vec<u8>;
+    fn decode(&self, buf: &mut BytesBuf) -> Result<Option<Vec<u8>>, Error> {
+        self.0.next_frame(buf)
+    }
+    fn encode(&self, resp: Vec<u8>, buf: &mut BytesBuf) {
+        let len = u32::try_from(resp.len()).expect("response is over the u32 length limit");
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(&resp);
+    }
+}
+
+//No framing, each request is whatever has arrived so far
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Echo;
+impl Codec for Echo {
+    type Req = Vec<u8>;
+    type Resp = Vec<u8>;
+    fn decode(&self, buf: &mut BytesBuf) -> Result<Option<Vec<u8>>, Error> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(buf.take()))
+    }
+    fn encode(&self, resp: Vec<u8>, buf: &mut BytesBuf) {
+        buf.extend_from_slice(&resp);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn codec_test() {
+        let mut buf = BytesBuf::from(&b"hi\nthere"[..]);
+        assert_eq!(
+            Line::default().decode(&mut buf).unwrap(),
+            Some("hi".to_string())
+        );
+        assert_eq!(Line::default().decode(&mut buf).unwrap(), None);
+        let mut invalid = BytesBuf::from(&[0xFF, b'\n'][..]);
+        Line::default()
+            .decode(&mut invalid)
+            .expect_err("Decoded invalid UTF-8");
+
+        let codec = LengthPrefix::default();
+        let mut out = BytesBuf::new();
+        codec.encode(b"abc".to_vec(), &mut out);
+        codec.encode(Vec::new(), &mut out);
+        println!("Encoded {:?}", &out[..]);
+        assert_eq!(codec.decode(&mut out).unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(codec.decode(&mut out).unwrap(), Some(Vec::new()));
+        assert!(out.is_empty());
+
+        let mut buf = BytesBuf::from(&b"raw"[..]);
+        assert_eq!(Echo.decode(&mut buf).unwrap(), Some(b"raw".to_vec()));
+        assert_eq!(Echo.decode(&mut buf).unwrap(), None);
+    }
+}
@@ -0,0 +1,534 @@
+use std::{
+    cell::UnsafeCell,
+    hint,
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        Arc,
+        atomic::{self, AtomicIsize, AtomicPtr, AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Steal<T> {
+    Empty,
+    Success(T),
+    //Lost a race with another thief or the owner, the deque may still have items
+    Retry,
+}
+
+//Bounded Chase-Lev deque, items are boxed so every slot access is a single atomic pointer
+struct Inner<T> {
+    top: AtomicIsize,
+    bottom: AtomicIsize,
+    buffer: Box<[AtomicPtr<T>]>,
+    mask: usize,
+}
+impl<T> Inner<T> {
+    fn slot(&self, index: isize) -> &AtomicPtr<T> {
+        &self.buffer[index as usize & self.mask]
+    }
+    fn len(&self) -> usize {
+        let bottom = self.bottom.load(Ordering::Acquire);
+        let top = self.top.load(Ordering::Acquire);
+        usize::try_from(bottom - top).unwrap_or(0)
+    }
+}
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let top = *self.top.get_mut();
+        let bottom = *self.bottom.get_mut();
+        for index in top..bottom {
+            let item = self.slot(index).load(Ordering::Relaxed);
+            unsafe { drop(Box::from_raw(item)) };
+        }
+    }
+}
+
+//Owner end of the deque, only the thread holding it may push or pop
+pub struct Worker<T> {
+    inner: Arc<Inner<T>>,
+}
+unsafe impl<T: Send> Send for Worker<T> {}
+
+impl<T> Worker<T> {
+    //Capacity is rounded up to the next power of two
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1).next_power_of_two();
+        Self {
+            inner: Arc::new(Inner {
+                top: AtomicIsize::new(0),
+                bottom: AtomicIsize::new(0),
+                buffer: (0..capacity)
+                    .map(|_| AtomicPtr::new(ptr::null_mut()))
+                    .collect(),
+                mask: capacity - 1,
+            }),
+        }
+    }
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+    pub fn capacity(&self) -> usize {
+        self.inner.buffer.len()
+    }
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    //Hands the item back when the deque is full
+    pub fn push(&self, item: T) -> Result<(), T> {
+        let inner = &self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed);
+        let top = inner.top.load(Ordering::Acquire);
+        if bottom - top >= inner.buffer.len() as isize {
+            return Err(item);
+        }
+        inner
+            .slot(bottom)
+            .store(Box::into_raw(Box::new(item)), Ordering::Release);
+        inner.bottom.store(bottom + 1, Ordering::Release);
+        Ok(())
+    }
+    pub fn pop(&self) -> Option<T> {
+        let inner = &self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed) - 1;
+        inner.bottom.store(bottom, Ordering::Relaxed);
+        atomic::fence(Ordering::SeqCst);
+        let top = inner.top.load(Ordering::Relaxed);
+
+        if top > bottom {
+            inner.bottom.store(bottom + 1, Ordering::Relaxed);
+            return None;
+        }
+        let item = inner.slot(bottom).load(Ordering::Acquire);
+        if top == bottom {
+            //Last item, thieves may be racing for it
+            let won = inner
+                .top
+                .compare_exchange(top, top + 1, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok();
+            inner.bottom.store(bottom + 1, Ordering::Relaxed);
+            if !won {
+                return None;
+            }
+        }
+        Some(unsafe { *Box::from_raw(item) })
+    }
+}
+
+//Thief end of the deque, takes from the opposite end to the owner
+pub struct Stealer<T> {
+    inner: Arc<Inner<T>>,
+}
+unsafe impl<T: Send> Send for Stealer<T> {}
+unsafe impl<T: Send> Sync for Stealer<T> {}
+
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+impl<T> Stealer<T> {
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn steal(&self) -> Steal<T> {
+        let inner = &self.inner;
+        let top = inner.top.load(Ordering::Acquire);
+        atomic::fence(Ordering::SeqCst);
+        let bottom = inner.bottom.load(Ordering::Acquire);
+        if top >= bottom {
+            return Steal::Empty;
+        }
+        //The slot can only be reused once top moves past it, which makes the exchange below fail
+        let item = inner.slot(top).load(Ordering::Acquire);
+        if inner
+            .top
+            .compare_exchange(top, top + 1, Ordering::SeqCst, Ordering::Relaxed)
+            .is_err()
+        {
+            return Steal::Retry;
+        }
+        Steal::Success(unsafe { *Box::from_raw(item) })
+    }
+}
+
+//Injector, Block, Slot and their constants below are adapted from crossbeam-deque's Injector
+//(https://github.com/crossbeam-rs/crossbeam), used under the MIT license. crossbeam-deque is
+//dual licensed under MIT or Apache-2.0, its MIT notice follows.
+//
+//Copyright (c) 2019 The Crossbeam Project Developers
+//
+//Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+//and associated documentation files (the "Software"), to deal in the Software without
+//restriction, including without limitation the rights to use, copy, modify, merge, publish,
+//distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+//Software is furnished to do so, subject to the following conditions:
+//
+//The above copyright notice and this permission notice shall be included in all copies or
+//substantial portions of the Software.
+//
+//THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+//BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+//NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+//DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+const WRITE: usize = 1;
+const READ: usize = 2;
+const DESTROY: usize = 4;
+const LAP: usize = 32;
+const BLOCK_CAP: usize = LAP - 1;
+const SHIFT: usize = 1;
+const HAS_NEXT: usize = 1;
+
+struct Slot<T> {
+    value: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicUsize,
+}
+impl<T> Slot<T> {
+    fn wait_write(&self) {
+        while self.state.load(Ordering::Acquire) & WRITE == 0 {
+            backoff();
+        }
+    }
+}
+
+struct Block<T> {
+    next: AtomicPtr<Block<T>>,
+    slots: [Slot<T>; BLOCK_CAP],
+}
+impl<T> Block<T> {
+    fn new() -> Box<Self> {
+        Box::new(Self {
+            next: AtomicPtr::new(ptr::null_mut()),
+            slots: std::array::from_fn(|_| Slot {
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+                state: AtomicUsize::new(0),
+            }),
+        })
+    }
+    fn wait_next(&self) -> *mut Block<T> {
+        loop {
+            let next = self.next.load(Ordering::Acquire);
+            if !next.is_null() {
+                return next;
+            }
+            backoff();
+        }
+    }
+    //Frees the block once every slot from start on has been read, whoever reads last frees it
+    unsafe fn destroy(this: *mut Block<T>, start: usize) {
+        unsafe {
+            for index in start..BLOCK_CAP - 1 {
+                let slot = &(*this).slots[index];
+                if slot.state.load(Ordering::Acquire) & READ == 0
+                    && slot.state.fetch_or(DESTROY, Ordering::AcqRel) & READ == 0
+                {
+                    return;
+                }
+            }
+            drop(Box::from_raw(this));
+        }
+    }
+}
+
+struct Position<T> {
+    index: AtomicUsize,
+    block: AtomicPtr<Block<T>>,
+}
+
+//Unbounded lock-free FIFO made of linked blocks, any thread may push or pop
+pub struct Injector<T> {
+    head: Position<T>,
+    tail: Position<T>,
+}
+unsafe impl<T: Send> Send for Injector<T> {}
+unsafe impl<T: Send> Sync for Injector<T> {}
+
+impl<T> Default for Injector<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T> Injector<T> {
+    pub fn new() -> Self {
+        let block = Box::into_raw(Block::new());
+        Self {
+            head: Position {
+                index: AtomicUsize::new(0),
+                block: AtomicPtr::new(block),
+            },
+            tail: Position {
+                index: AtomicUsize::new(0),
+                block: AtomicPtr::new(block),
+            },
+        }
+    }
+    pub fn push(&self, value: T) {
+        let mut tail = self.tail.index.load(Ordering::Acquire);
+        let mut block = self.tail.block.load(Ordering::Acquire);
+        let mut next_block = None;
+
+        loop {
+            let offset = (tail >> SHIFT) % LAP;
+            //Another thread is installing the next block
+            if offset == BLOCK_CAP {
+                backoff();
+                tail = self.tail.index.load(Ordering::Acquire);
+                block = self.tail.block.load(Ordering::Acquire);
+                continue;
+            }
+            if offset + 1 == BLOCK_CAP && next_block.is_none() {
+                next_block = Some(Block::new());
+            }
+
+            let new_tail = tail + (1 << SHIFT);
+            match self.tail.index.compare_exchange_weak(
+                tail,
+                new_tail,
+                Ordering::SeqCst,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => unsafe {
+                    if offset + 1 == BLOCK_CAP {
+                        let next_block = Box::into_raw(next_block.unwrap());
+                        let next_index = new_tail.wrapping_add(1 << SHIFT);
+                        self.tail.block.store(next_block, Ordering::Release);
+                        self.tail.index.store(next_index, Ordering::Release);
+                        (*block).next.store(next_block, Ordering::Release);
+                    }
+                    let slot = &(*block).slots[offset];
+                    slot.value.get().write(MaybeUninit::new(value));
+                    slot.state.fetch_or(WRITE, Ordering::Release);
+                    return;
+                },
+                Err(current) => {
+                    tail = current;
+                    block = self.tail.block.load(Ordering::Acquire);
+                }
+            }
+        }
+    }
+    pub fn pop(&self) -> Option<T> {
+        let mut head = self.head.index.load(Ordering::Acquire);
+        let mut block = self.head.block.load(Ordering::Acquire);
+
+        loop {
+            let offset = (head >> SHIFT) % LAP;
+            //Another thread is moving the head to the next block
+            if offset == BLOCK_CAP {
+                backoff();
+                head = self.head.index.load(Ordering::Acquire);
+                block = self.head.block.load(Ordering::Acquire);
+                continue;
+            }
+
+            let mut new_head = head + (1 << SHIFT);
+            if new_head & HAS_NEXT == 0 {
+                atomic::fence(Ordering::SeqCst);
+                let tail = self.tail.index.load(Ordering::Relaxed);
+                if head >> SHIFT == tail >> SHIFT {
+                    return None;
+                }
+                if (head >> SHIFT) / LAP != (tail >> SHIFT) / LAP {
+                    new_head |= HAS_NEXT;
+                }
+            }
+
+            match self.head.index.compare_exchange_weak(
+                head,
+                new_head,
+                Ordering::SeqCst,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => unsafe {
+                    if offset + 1 == BLOCK_CAP {
+                        let next = (*block).wait_next();
+                        let mut next_index = (new_head & !HAS_NEXT).wrapping_add(1 << SHIFT);
+                        if !(*next).next.load(Ordering::Relaxed).is_null() {
+                            next_index |= HAS_NEXT;
+                        }
+                        self.head.block.store(next, Ordering::Release);
+                        self.head.index.store(next_index, Ordering::Release);
+                    }
+
+                    let slot = &(*block).slots[offset];
+                    slot.wait_write();
+                    let value = slot.value.get().read().assume_init();
+
+                    if offset + 1 == BLOCK_CAP {
+                        Block::destroy(block, 0);
+                    } else if slot.state.fetch_or(READ, Ordering::AcqRel) & DESTROY != 0 {
+                        Block::destroy(block, offset + 1);
+                    }
+                    return Some(value);
+                },
+                Err(current) => {
+                    head = current;
+                    block = self.head.block.load(Ordering::Acquire);
+                }
+            }
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        let head = self.head.index.load(Ordering::SeqCst);
+        let tail = self.tail.index.load(Ordering::SeqCst);
+        head >> SHIFT == tail >> SHIFT
+    }
+}
+impl<T> Drop for Injector<T> {
+    fn drop(&mut self) {
+        let mut head = *self.head.index.get_mut() & !((1 << SHIFT) - 1);
+        let tail = *self.tail.index.get_mut() & !((1 << SHIFT) - 1);
+        let mut block = *self.head.block.get_mut();
+
+        unsafe {
+            while head != tail {
+                let offset = (head >> SHIFT) % LAP;
+                if offset < BLOCK_CAP {
+                    let slot = &mut (*block).slots[offset];
+                    slot.value.get_mut().assume_init_drop();
+                } else {
+                    let next = *(*block).next.get_mut();
+                    drop(Box::from_raw(block));
+                    block = next;
+                }
+                head = head.wrapping_add(1 << SHIFT);
+            }
+            if !block.is_null() {
+                drop(Box::from_raw(block));
+            }
+        }
+    }
+}
+
+fn backoff() {
+    for _ in 0..16 {
+        hint::spin_loop();
+    }
+    thread::yield_now();
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn deque_test() {
+        let worker: Worker<u32> = Worker::new(3);
+        assert_eq!(worker.capacity(), 4);
+        let stealer = worker.stealer();
+        for i in 0..4 {
+            worker.push(i).unwrap();
+        }
+        assert_eq!(worker.push(4), Err(4));
+
+        //Owner is LIFO, thieves take the oldest item
+        assert_eq!(worker.pop(), Some(3));
+        assert_eq!(stealer.steal(), Steal::Success(0));
+        assert_eq!(worker.pop(), Some(2));
+        assert_eq!(worker.pop(), Some(1));
+        assert_eq!(worker.pop(), None);
+        assert_eq!(stealer.steal(), Steal::Empty);
+        assert!(worker.is_empty());
+
+        //Slots are reused after wrapping around
+        for i in 10..14 {
+            worker.push(i).unwrap();
+        }
+        assert_eq!(stealer.steal(), Steal::Success(10));
+        worker.push(14).unwrap();
+        assert_eq!(stealer.len(), 4);
+    }
+
+    #[test]
+    fn injector_test() {
+        let injector: Injector<usize> = Injector::new();
+        assert_eq!(injector.pop(), None);
+        for i in 0..LAP * 3 {
+            injector.push(i);
+        }
+        for i in 0..LAP * 2 {
+            assert_eq!(injector.pop(), Some(i));
+        }
+        assert!(!injector.is_empty());
+        //The rest is freed by drop
+        injector.push(1000);
+    }
+
+    #[test]
+    fn concurrent_steal_test() {
+        const ITEMS: usize = 100_000;
+        let worker: Worker<usize> = Worker::new(64);
+        let injector = Arc::new(Injector::new());
+        let seen = Arc::new(Mutex::new(HashSet::new()));
+
+        let thieves: Vec<_> = (0..3)
+            .map(|_| {
+                let stealer = worker.stealer();
+                let injector = Arc::clone(&injector);
+                let seen = Arc::clone(&seen);
+                thread::spawn(move || {
+                    let mut taken = Vec::new();
+                    loop {
+                        match stealer.steal() {
+                            Steal::Success(item) => taken.push(item),
+                            Steal::Retry => continue,
+                            Steal::Empty => match injector.pop() {
+                                Some(usize::MAX) => break,
+                                Some(item) => taken.push(item),
+                                None => thread::yield_now(),
+                            },
+                        }
+                    }
+                    let mut seen = seen.lock().unwrap();
+                    for item in taken {
+                        assert!(seen.insert(item), "Item {item} was taken twice");
+                    }
+                })
+            })
+            .collect();
+
+        let mut popped = Vec::new();
+        for item in 0..ITEMS {
+            if let Err(item) = worker.push(item) {
+                injector.push(item);
+            }
+            if item % 3 == 0
+                && let Some(item) = worker.pop()
+            {
+                popped.push(item);
+            }
+        }
+        while let Some(item) = worker.pop() {
+            popped.push(item);
+        }
+        for _ in 0..3 {
+            injector.push(usize::MAX);
+        }
+        for thief in thieves {
+            thief.join().unwrap();
+        }
+
+        let mut seen = seen.lock().unwrap();
+        for item in popped {
+            assert!(seen.insert(item), "Item {item} was taken twice");
+        }
+        assert_eq!(seen.len(), ITEMS, "Items were lost");
+    }
+}
@@ -0,0 +1,124 @@
+use std::io::{Error, ErrorKind};
+
+use crate::bytes::BytesBuf;
+
+//Splits a byte stream into messages
+pub trait Framer {
+    //Removes one complete frame from the front of buf, None until enough bytes have arrived
+    fn next_frame(&self, buf: &mut BytesBuf) -> Result<Option<Vec<u8>>, Error>;
+}
+
+fn too_long(len: usize, max_len: usize) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("frame of {len} bytes is over the {max_len} byte limit"),
+    )
+}
+
+//Newline delimited, the \n and a \r before it are stripped
+#[derive(Debug, Clone, Copy)]
+pub struct Lines {
+    pub max_len: usize,
+}
+impl Default for Lines {
+    fn default() -> Self {
+        Self { max_len: 64 * 1024 }
+    }
+}
+impl Framer for Lines {
+    fn next_frame(&self, buf: &mut BytesBuf) -> Result<Option<Vec<u8>>, Error> {
+        let Some(end) = buf.iter().position(|byte| *byte == b'\n') else {
+            if buf.len() > self.max_len {
+                return Err(too_long(buf.len(), self.max_len));
+            }
+            return Ok(None);
+        };
+        if end > self.max_len {
+            return Err(too_long(end, self.max_len));
+        }
+        let mut line = buf.split_to(end + 1);
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+}
+
+//Big-endian u32 length followed by that many bytes
+#[derive(Debug, Clone, Copy)]
+pub struct LengthPrefixed {
+    pub max_len: usize,
+}
+impl Default for LengthPrefixed {
+    fn default() -> Self {
+        Self {
+            max_len: 16 * 1024 * 1024,
+        }
+    }
+}
+impl Framer for LengthPrefixed {
+    fn next_frame(&self, buf: &mut BytesBuf) -> Result<Option<Vec<u8>>, Error> {
+        let Some(prefix) = buf.get(..4) else {
+            return Ok(None);
+        };
+        let len = usize::try_from(u32::from_be_bytes(prefix.try_into().unwrap())).unwrap();
+        if len > self.max_len {
+            return Err(too_long(len, self.max_len));
+        }
+        if buf.len() < 4 + len {
+            return Ok(None);
+        }
+        buf.advance(4);
+        Ok(Some(buf.split_to(len)))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FixedSize(pub usize);
+impl Framer for FixedSize {
+    fn next_frame(&self, buf: &mut BytesBuf) -> Result<Option<Vec<u8>>, Error> {
+        if buf.len() < self.0 {
+            return Ok(None);
+        }
+        Ok(Some(buf.split_to(self.0)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frames<F: Framer>(framer: &F, buf: &mut BytesBuf) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while let Some(frame) = framer.next_frame(buf).unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn framer_test() {
+        let mut buf = BytesBuf::from(&b"one\r\ntwo\nthr"[..]);
+        assert_eq!(
+            frames(&Lines::default(), &mut buf),
+            vec![b"one".to_vec(), b"two".to_vec()]
+        );
+        buf.extend_from_slice(b"ee\n");
+        assert_eq!(frames(&Lines::default(), &mut buf), vec![b"three".to_vec()]);
+
+        let mut buf = BytesBuf::from(&[0, 0, 0, 3, b'a', b'b', b'c', 0, 0, 0, 2, b'd'][..]);
+        let framer = LengthPrefixed::default();
+        assert_eq!(frames(&framer, &mut buf), vec![b"abc".to_vec()]);
+        assert_eq!(buf.len(), 5, "Partial frame was consumed");
+
+        let mut buf = BytesBuf::from(&b"abcdefg"[..]);
+        assert_eq!(frames(&FixedSize(3), &mut buf).len(), 2);
+        assert_eq!(&buf[..], b"g");
+
+        let mut buf = BytesBuf::from(&b"this line is too long"[..]);
+        let err = Lines { max_len: 8 }.next_frame(&mut buf).unwrap_err();
+        println!("Error {err}");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,630 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    sync::{Arc, Mutex},
+};
+
+use crate::{bytes::BytesBuf, codec::Codec, watcher::Telementry};
+
+//Request line and headers together, anything bigger is rejected
+const MAX_HEAD: usize = 64 * 1024;
+const MAX_BODY: usize = 16 * 1024 * 1024;
+
+type HttpFunc = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+//Well formed but asks for something the parser does not do, answered with 501
+fn unsupported(message: &str) -> Error {
+    Error::new(ErrorKind::Unsupported, message.to_string())
+}
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    //Everything after the ?, empty if there was none
+    pub query: String,
+    //1 for HTTP/1.1, 0 for HTTP/1.0
+    pub minor_version: u8,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+impl Request {
+    //Names are matched case-insensitively, the first match wins
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+    pub fn keep_alive(&self) -> bool {
+        match self.header("connection") {
+            Some(value) if value.eq_ignore_ascii_case("close") => false,
+            Some(value) if value.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.minor_version >= 1,
+        }
+    }
+    //Only a lone chunked coding is understood. Anything else, or a Content-Length next to it,
+    //leaves the body length open to interpretation so the request is refused
+    fn chunked(&self) -> Result<bool, Error> {
+        let mut codings = self
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("transfer-encoding"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+            .filter(|coding| !coding.is_empty());
+        let Some(first) = codings.next() else {
+            return Ok(false);
+        };
+        if self.header("content-length").is_some() {
+            return Err(invalid("both transfer-encoding and content-length are set"));
+        }
+        if !first.eq_ignore_ascii_case("chunked") || codings.next().is_some() {
+            return Err(unsupported("transfer-encoding other than chunked"));
+        }
+        Ok(true)
+    }
+    //Repeated Content-Length headers have to agree and be plain digits, for the same reason
+    fn content_length(&self) -> Result<usize, Error> {
+        let mut len = None;
+        for (_, value) in self
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        {
+            if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(invalid("malformed content-length"));
+            }
+            let value = value
+                .parse::<usize>()
+                .map_err(|_| invalid("malformed content-length"))?;
+            if len.is_some_and(|len| len != value) {
+                return Err(invalid("conflicting content-length headers"));
+            }
+            len = Some(value);
+        }
+        Ok(len.unwrap_or(0))
+    }
+    //Removes one request from the front of buf, None until all of it has arrived.
+    //Nothing is consumed until the request is complete, so it can be called again on every read.
+    //Each call starts over from the front, a chunked body is only copied out once it is complete
+    pub fn parse(buf: &mut BytesBuf) -> Result<Option<Request>, Error> {
+        let Some(head_len) = find(buf, b"\r\n\r\n") else {
+            if buf.len() > MAX_HEAD {
+                return Err(invalid("request head is too large"));
+            }
+            return Ok(None);
+        };
+        if head_len > MAX_HEAD {
+            return Err(invalid("request head is too large"));
+        }
+        let head = std::str::from_utf8(&buf[..head_len])
+            .map_err(|_| invalid("request head is not UTF-8"))?;
+        let mut lines = head.split("\r\n");
+        let mut request = Self::parse_request_line(lines.next().unwrap_or_default())?;
+        for line in lines {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid("malformed header"))?;
+            request
+                .headers
+                .push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let body_start = head_len + 4;
+        let consumed = if request.chunked()? {
+            let Some((consumed, body)) = parse_chunked(&buf[body_start..])? else {
+                return Ok(None);
+            };
+            request.body = body;
+            body_start + consumed
+        } else {
+            let len = request.content_length()?;
+            if len > MAX_BODY {
+                return Err(invalid("request body is too large"));
+            }
+            if buf.len() < body_start + len {
+                return Ok(None);
+            }
+            request.body = buf[body_start..body_start + len].to_vec();
+            body_start + len
+        };
+        buf.advance(consumed);
+        Ok(Some(request))
+    }
+    fn parse_request_line(line: &str) -> Result<Request, Error> {
+        let mut parts = line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid("malformed request line"));
+        };
+        let minor_version = match version {
+            "HTTP/1.1" => 1,
+            "HTTP/1.0" => 0,
+            _ => return Err(invalid("unsupported HTTP version")),
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Ok(Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            minor_version,
+            headers: Vec::new(),
+            body: Vec::new(),
+        })
+    }
+}
+
+//Returns the bytes the chunked body took up and the decoded body, trailers are skipped.
+//Until the last chunk is in only the size lines are read, nothing is copied
+fn parse_chunked(bytes: &[u8]) -> Result<Option<(usize, Vec<u8>)>, Error> {
+    let mut chunks = Vec::new();
+    let mut total = 0;
+    let mut pos = 0;
+    loop {
+        let Some(line_len) = find(&bytes[pos..], b"\r\n") else {
+            return Ok(None);
+        };
+        let line = std::str::from_utf8(&bytes[pos..pos + line_len])
+            .map_err(|_| invalid("malformed chunk size"))?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(invalid("malformed chunk size"));
+        }
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("malformed chunk size"))?;
+        pos += line_len + 2;
+        if size == 0 {
+            break;
+        }
+        //total never goes past MAX_BODY, so this can not underflow and pos can not overflow
+        if size > MAX_BODY - total {
+            return Err(invalid("request body is too large"));
+        }
+        if bytes.len() < pos + size + 2 {
+            return Ok(None);
+        }
+        if &bytes[pos + size..pos + size + 2] != b"\r\n" {
+            return Err(invalid("malformed chunk"));
+        }
+        chunks.push(pos..pos + size);
+        total += size;
+        pos += size + 2;
+    }
+    loop {
+        let Some(line_len) = find(&bytes[pos..], b"\r\n") else {
+            return Ok(None);
+        };
+        pos += line_len + 2;
+        if line_len == 0 {
+            let mut body = Vec::with_capacity(total);
+            for chunk in chunks {
+                body.extend_from_slice(&bytes[chunk]);
+            }
+            return Ok(Some((pos, body)));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Full(Vec<u8>),
+    //Sent with Transfer-Encoding: chunked, joined into one body for HTTP/1.0 clients
+    Chunked(Vec<Vec<u8>>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Body::Full(Vec::new()),
+        }
+    }
+    pub fn text<S: Into<String>>(status: u16, text: S) -> Self {
+        Self::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(text.into().into_bytes())
+    }
+    pub fn json<S: Into<String>>(status: u16, json: S) -> Self {
+        Self::new(status)
+            .header("Content-Type", "application/json")
+            .body(json.into().into_bytes())
+    }
+    pub fn chunked(status: u16, chunks: Vec<Vec<u8>>) -> Self {
+        Self {
+            body: Body::Chunked(chunks),
+            ..Self::new(status)
+        }
+    }
+    pub fn header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = Body::Full(body);
+        self
+    }
+}
+
+//A response with what its encoding depends on from the request, the Resp of Http
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub response: Response,
+    minor_version: u8,
+    head: bool,
+    keep_alive: bool,
+}
+impl Reply {
+    pub fn new(request: &Request, response: Response) -> Self {
+        Self {
+            response,
+            minor_version: request.minor_version,
+            head: request.method == "HEAD",
+            keep_alive: request.keep_alive(),
+        }
+    }
+    //For requests that could not be parsed, so there is no version or method to answer to.
+    //Sent as HTTP/1.1 and closes the connection
+    pub fn error(response: Response) -> Self {
+        Self {
+            response,
+            minor_version: 1,
+            head: false,
+            keep_alive: false,
+        }
+    }
+    //Content-Length, Transfer-Encoding and Connection are filled in here
+    pub fn encode(self, buf: &mut BytesBuf) {
+        let response = self.response;
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            response.status,
+            reason(response.status)
+        );
+        for (name, value) in &response.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self.keep_alive {
+            head.push_str("Connection: close\r\n");
+        } else if self.minor_version == 0 {
+            head.push_str("Connection: keep-alive\r\n");
+        }
+        let body = match response.body {
+            Body::Chunked(chunks) if self.minor_version == 0 => chunks.concat(),
+            Body::Full(body) => body,
+            Body::Chunked(chunks) => {
+                head.push_str("Transfer-Encoding: chunked\r\n\r\n");
+                buf.extend_from_slice(head.as_bytes());
+                if self.head {
+                    return;
+                }
+                for chunk in chunks.iter().filter(|chunk| !chunk.is_empty()) {
+                    buf.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                    buf.extend_from_slice(chunk);
+                    buf.extend_from_slice(b"\r\n");
+                }
+                buf.extend_from_slice(b"0\r\n\r\n");
+                return;
+            }
+        };
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+        buf.extend_from_slice(head.as_bytes());
+        if !self.head {
+            buf.extend_from_slice(&body);
+        }
+    }
+}
+
+//HTTP/1.x for AsyncListener::serve_codec. Requests that fail to parse get a 400, or a 501 when
+//they need something the parser does not support, and the connection is closed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Http;
+impl Codec for Http {
+    type Req = Request;
+    type Resp = Reply;
+    fn decode(&self, buf: &mut BytesBuf) -> Result<Option<Request>, Error> {
+        Request::parse(buf)
+    }
+    fn encode(&self, resp: Reply, buf: &mut BytesBuf) {
+        resp.encode(buf);
+    }
+    fn reject(&self, err: Error) -> Result<Reply, Error> {
+        let status = if err.kind() == ErrorKind::Unsupported {
+            501
+        } else {
+            400
+        };
+        Ok(Reply::error(Response::text(status, format!("{err}\n"))))
+    }
+    fn keep_open(&self, resp: &Reply) -> bool {
+        resp.keep_alive
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+//Exact path matches, HEAD falls back to the GET handler
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: HashMap<String, Vec<(String, HttpFunc)>>,
+}
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn route<F>(mut self, method: &str, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + 'static + Send + Sync,
+    {
+        self.routes
+            .entry(path.to_string())
+            .or_default()
+            .push((method.to_string(), Arc::new(handler)));
+        self
+    }
+    pub fn get<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + 'static + Send + Sync,
+    {
+        self.route("GET", path, handler)
+    }
+    pub fn post<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + 'static + Send + Sync,
+    {
+        self.route("POST", path, handler)
+    }
+    //Serves Telementry::to_json on GET
+    pub fn telemetry(self, path: &str, telemetry: Arc<Mutex<Telementry>>) -> Self {
+        self.get(path, move |_| {
+            Response::json(200, telemetry.lock().unwrap().to_json())
+        })
+    }
+    pub fn handle(&self, request: &Request) -> Response {
+        let Some(handlers) = self.routes.get(&request.path) else {
+            return Response::text(404, "Not Found\n");
+        };
+        let find = |method: &str| {
+            handlers
+                .iter()
+                .find(|(allowed, _)| allowed == method)
+                .map(|(_, handler)| handler)
+        };
+        let handler = match request.method.as_str() {
+            "HEAD" => find("HEAD").or_else(|| find("GET")),
+            method => find(method),
+        };
+        match handler {
+            Some(handler) => handler(request),
+            None => {
+                let allowed: Vec<&str> =
+                    handlers.iter().map(|(method, _)| method.as_str()).collect();
+                Response::text(405, "Method Not Allowed\n").header("Allow", allowed.join(", "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{spawn_server, test_server};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn parser_test() {
+        let raw = b"POST /echo?x=1 HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.0\r\n\r\n";
+        let mut buf = BytesBuf::new();
+        //Fed one byte at a time, nothing comes out until a request is complete
+        let mut requests = Vec::new();
+        for byte in raw {
+            buf.extend_from_slice(&[*byte]);
+            while let Some(request) = Request::parse(&mut buf).unwrap() {
+                requests.push(request);
+            }
+        }
+        println!("Requests {requests:?}");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/echo");
+        assert_eq!(requests[0].query, "x=1");
+        assert_eq!(requests[0].header("HOST"), Some("a"));
+        assert_eq!(requests[0].body, b"hello");
+        assert!(requests[0].keep_alive());
+        assert!(!requests[1].keep_alive());
+
+        let mut buf = BytesBuf::from(
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;x=y\r\nde\r\n0\r\n\r\n"[..],
+        );
+        let request = Request::parse(&mut buf).unwrap().unwrap();
+        assert_eq!(request.body, b"abcde");
+        assert!(buf.is_empty());
+
+        let mut buf = BytesBuf::from(&b"GARBAGE\r\n\r\n"[..]);
+        Request::parse(&mut buf).expect_err("Parsed a malformed request line");
+
+        //Codings the parser can not undo, and lengths given two ways, are refused
+        let mut buf = BytesBuf::from(
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n"[..],
+        );
+        let err = Request::parse(&mut buf).expect_err("Parsed an unsupported coding");
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        let mut buf = BytesBuf::from(
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\nabc"[..],
+        );
+        let err = Request::parse(&mut buf).expect_err("Parsed conflicting lengths");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        //Lengths a proxy could read differently are refused
+        for raw in [
+            &b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 5\r\n\r\nabcde"[..],
+            &b"POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\nabc"[..],
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+3\r\nabc\r\n0\r\n\r\n"[..],
+        ] {
+            let err =
+                Request::parse(&mut BytesBuf::from(raw)).expect_err("Parsed an unclear length");
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+        let mut buf = BytesBuf::from(
+            &b"POST / HTTP/1.1\r\nContent-Length: 3\r\ncontent-length: 3\r\n\r\nabc"[..],
+        );
+        assert_eq!(Request::parse(&mut buf).unwrap().unwrap().body, b"abc");
+
+        //Chunk sizes that would overflow the running total are refused, not wrapped
+        let mut buf = BytesBuf::from(
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\nxx"[..],
+        );
+        let err = Request::parse(&mut buf).expect_err("Parsed an oversized chunk");
+        assert_eq!(err.to_string(), "request body is too large");
+    }
+
+    #[test]
+    fn router_test() {
+        let router = Router::new()
+            .get("/", |_| Response::text(200, "root"))
+            .post("/", |request| Response::new(201).body(request.body.clone()));
+        let mut request = Request::parse_request_line("GET / HTTP/1.1").unwrap();
+        assert_eq!(router.handle(&request).status, 200);
+        request.method = "HEAD".to_string();
+        assert_eq!(router.handle(&request).status, 200);
+        request.method = "DELETE".to_string();
+        let response = router.handle(&request);
+        assert_eq!(response.status, 405);
+        println!("Headers {:?}", response.headers);
+        request.path = "/missing".to_string();
+        assert_eq!(router.handle(&request).status, 404);
+    }
+
+    #[test]
+    fn serve_http_test() {
+        let telemetry = Arc::new(Mutex::new(Telementry::default()));
+        telemetry.lock().unwrap().watch_connection();
+        let done = telemetry.lock().unwrap().watch_connection();
+        telemetry.lock().unwrap().stop_watching_connection(done);
+        let router = Router::new()
+            .get("/hello", |_| Response::text(200, "hi"))
+            .get("/stream", |_| {
+                Response::chunked(200, vec![b"one ".to_vec(), b"two".to_vec()])
+            })
+            .telemetry("/stats", telemetry);
+        let running = spawn_server(test_server(2), |server| server.serve_http(-1, router));
+
+        let mut stream =
+            TcpStream::connect(running.addr()).expect("Could not connect to test server");
+        //Two requests on one connection, the first split across writes
+        stream.write_all(b"GET /hel").unwrap();
+        thread::sleep(Duration::from_millis(20));
+        stream
+            .write_all(b"lo HTTP/1.1\r\n\r\nGET /stream HTTP/1.1\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream
+            .write_all(
+                b"GET /stats HTTP/1.1\r\n\r\nGET /stats HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        println!("{response}");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 2\r\n\r\nhi"));
+        assert!(
+            response
+                .contains("Transfer-Encoding: chunked\r\n\r\n4\r\none \r\n3\r\ntwo\r\n0\r\n\r\n")
+        );
+        assert!(response.contains("Connection: close\r\n"));
+        //Reading the stats does not use up the finished timings
+        let stats = "{\"connections\":2,\"finished\":1,\"average\":0,\"min\":0,\"max\":0}";
+        assert_eq!(response.matches(stats).count(), 2);
+        assert!(response.ends_with(stats));
+
+        running.stop();
+    }
+
+    #[test]
+    fn half_close_test() {
+        let body: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = body.clone();
+        let router = Router::new().get("/big", move |_| Response::new(200).body(body.clone()));
+        let running = spawn_server(test_server(2), |server| server.serve_http(-1, router));
+
+        let mut stream =
+            TcpStream::connect(running.addr()).expect("Could not connect to test server");
+        stream.write_all(b"GET /big HTTP/1.1\r\n\r\n").unwrap();
+        //Done sending but still reading, the whole response has to arrive before EOF
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        println!("Received {} bytes", response.len());
+        let head_len = find(&response, b"\r\n\r\n").unwrap() + 4;
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response[head_len..] == expected, "Response was cut short");
+
+        running.stop();
+    }
+
+    #[test]
+    fn reject_test() {
+        let router =
+            Router::new().post("/", |request| Response::new(200).body(request.body.clone()));
+        let running = spawn_server(test_server(2), |server| server.serve_http(-1, router));
+
+        for (raw, status) in [
+            (
+                &b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"[..],
+                "501 Not Implemented",
+            ),
+            (
+                &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 0\r\n\r\n"[..],
+                "400 Bad Request",
+            ),
+        ] {
+            let mut stream =
+                TcpStream::connect(running.addr()).expect("Could not connect to test server");
+            stream.write_all(raw).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            println!("{response}");
+            assert!(response.starts_with(&format!("HTTP/1.1 {status}\r\n")));
+            assert!(response.contains("Connection: close\r\n"));
+        }
+
+        running.stop();
+    }
+}
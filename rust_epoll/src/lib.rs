@@ -1,46 +1,784 @@
 use std::{
-    net::{TcpListener, ToSocketAddrs},
+    collections::{HashMap, VecDeque},
+    ffi::c_int,
+    io::Error,
+    net::{Shutdown, TcpListener, ToSocketAddrs, UdpSocket},
+    os::{
+        fd::AsRawFd,
+        unix::net::{SocketAddr as UnixSocketAddr, UnixListener},
+    },
     sync::{Arc, Mutex},
+    thread::sleep,
+    time::{Duration, Instant},
 };
 
-use polller::{Connection, Poller};
-use pool::{ThreadErr, ThreadFunc, ThreadPool};
+use bytes::BytesBuf;
+use codec::Codec;
+use http::{Http, Reply, Router};
+use polller::{
+    Connection, ConnectionState, Datagram, Event, Expiry, Interest, ListenerMode, PollError,
+    Poller, Readiness, Timeouts, TimerHandle, Waker,
+};
+use pool::{ShutdownMode, ThreadErr, ThreadPool};
+use stream::ListenSocket;
 
+pub mod bytes;
+pub mod codec;
+pub mod deque;
+pub mod framer;
+pub mod http;
 pub mod polller;
 pub mod pool;
+pub mod slab;
+pub mod stream;
+pub mod timer;
+pub mod udp;
 pub mod watcher;
 
-pub struct AsyncListener<const S: usize> {
-    server: TcpListener,
+pub type ReadyFunc = Arc<dyn Fn(usize, Readiness) -> Result<(), ThreadErr> + Send + Sync>;
+//Handlers from a Registrar, None removes one
+type Registered = Arc<Mutex<Vec<(u64, Option<ReadyFunc>)>>>;
+type ConnFunc = Arc<dyn Fn(usize, Connection) -> Result<(), ThreadErr> + Send + Sync>;
+type TimerFunc = Arc<dyn Fn() -> Result<(), ThreadErr> + Send + Sync>;
+type DatagramFunc = Arc<dyn Fn(usize, Datagram) -> Result<(), ThreadErr> + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    Retry,
+    GiveUp,
+}
+impl ErrorPolicy {
+    //Signals, fd exhaustion and events for unknown tokens are recoverable, anything else stops the server.
+    //Retrying on fd exhaustion does not spin, the poller disarms its listeners for a while
+    pub fn default_for(err: &PollError) -> Self {
+        match err {
+            PollError::Interrupted | PollError::TooManyFiles(_) | PollError::StaleEvent(_) => {
+                ErrorPolicy::Retry
+            }
+            PollError::Io(_) => ErrorPolicy::GiveUp,
+        }
+    }
+}
+
+//Where a timer callback runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerDispatch {
+    //On the thread running serve, between polls. Keep it short, nothing else is handled meanwhile
+    Inline,
+    Pool,
+}
+
+pub struct AsyncListener {
     poller: Poller,
-    thread_pool: Arc<ThreadPool<S>>,
+    thread_pool: Arc<ThreadPool>,
+    sources: HashMap<u64, ReadyFunc>,
+    timers: HashMap<u64, (TimerFunc, TimerDispatch)>,
+    datagrams: HashMap<usize, DatagramFunc>,
+    registered: Registered,
+    shutdown_grace: Duration,
 }
-impl<const S: usize> AsyncListener<S> {
+impl AsyncListener {
     pub fn new<A: ToSocketAddrs>(addr: A, max_events: u32) -> Self {
-        let server = TcpListener::bind(addr).unwrap();
-        let poller = Poller::new(max_events, &server).unwrap();
-        Self {
-            server,
+        Self::with_listener(TcpListener::bind(addr).unwrap(), max_events).unwrap()
+    }
+    //Build the address with UnixSocketAddr::from_pathname or from_abstract_name.
+    //A leftover socket file at the path makes the bind fail, remove it first
+    pub fn new_unix(addr: &UnixSocketAddr, max_events: u32) -> Result<Self, Error> {
+        Self::with_listener(UnixListener::bind_addr(addr)?, max_events)
+    }
+    fn with_listener<L: Into<ListenSocket>>(listener: L, max_events: u32) -> Result<Self, Error> {
+        let mut poller = Poller::new(max_events)?;
+        poller.add_listener(listener)?;
+        Ok(Self {
             poller,
-            thread_pool: Arc::new(ThreadPool::new()),
+            thread_pool: Arc::new(ThreadPool::default()),
+            sources: HashMap::new(),
+            timers: HashMap::new(),
+            datagrams: HashMap::new(),
+            registered: Arc::new(Mutex::new(Vec::new())),
+            shutdown_grace: Duration::from_secs(5),
+        })
+    }
+    //Replaces the default pool, which has one worker per available core
+    pub fn with_thread_pool(mut self, pool: ThreadPool) -> Self {
+        self.thread_pool = Arc::new(pool);
+        self
+    }
+    //Handlers can spawn work on the same pool and collect it through the TaskHandle
+    pub fn thread_pool(&self) -> Arc<ThreadPool> {
+        Arc::clone(&self.thread_pool)
+    }
+    //The listener created by new has id 0, each call here returns the next id
+    pub fn add_listener<A: ToSocketAddrs>(&mut self, addr: A) -> Result<usize, Error> {
+        self.poller.add_listener(TcpListener::bind(addr)?)
+    }
+    pub fn add_unix_listener(&mut self, addr: &UnixSocketAddr) -> Result<usize, Error> {
+        self.poller.add_listener(UnixListener::bind_addr(addr)?)
+    }
+    pub fn set_listener_mode(&mut self, id: usize, mode: ListenerMode) -> Result<(), Error> {
+        self.poller.set_listener_mode(id, mode)
+    }
+    pub fn listener(&self, id: usize) -> Option<&TcpListener> {
+        self.poller.listener(id)
+    }
+    pub fn unix_listener(&self, id: usize) -> Option<&UnixListener> {
+        self.poller.unix_listener(id)
+    }
+    //Each datagram runs the handler on the pool, reply through Datagram::reply or udp::send_batch
+    pub fn add_udp<A, H>(&mut self, addr: A, handler: H) -> Result<usize, Error>
+    where
+        A: ToSocketAddrs,
+        H: Fn(usize, Datagram) -> Result<(), ThreadErr> + 'static + Send + Sync,
+    {
+        let id = self.poller.add_udp(UdpSocket::bind(addr)?)?;
+        self.datagrams.insert(id, Arc::new(handler));
+        Ok(id)
+    }
+    pub fn udp_socket(&self, id: usize) -> Option<&Arc<UdpSocket>> {
+        self.poller.udp_socket(id)
+    }
+    //How many events one wakeup can hand over
+    pub fn set_max_events(&mut self, max_events: u32) {
+        self.poller.set_max_events(max_events);
+    }
+    //Stalled connections are reported as TimedOut instead of Closed
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) -> Result<(), Error> {
+        self.poller.set_timeouts(timeouts)
+    }
+    //Unread bytes a connection may hold before the poller stops reading it
+    pub fn set_inbound_limit(&mut self, limit: usize) {
+        self.poller.set_inbound_limit(limit);
+    }
+    //Runs once after the delay unless cancelled first
+    pub fn schedule_after<F>(
+        &mut self,
+        delay: Duration,
+        dispatch: TimerDispatch,
+        callback: F,
+    ) -> Result<TimerHandle, Error>
+    where
+        F: Fn() -> Result<(), ThreadErr> + 'static + Send + Sync,
+    {
+        let handle = self.poller.add_timer(delay, None)?;
+        self.timers
+            .insert(handle.id(), (Arc::new(callback), dispatch));
+        Ok(handle)
+    }
+    //Runs every interval until cancelled, ticks missed while the loop was busy run once
+    pub fn schedule_every<F>(
+        &mut self,
+        interval: Duration,
+        dispatch: TimerDispatch,
+        callback: F,
+    ) -> Result<TimerHandle, Error>
+    where
+        F: Fn() -> Result<(), ThreadErr> + 'static + Send + Sync,
+    {
+        let handle = self.poller.add_timer(interval, Some(interval))?;
+        self.timers
+            .insert(handle.id(), (Arc::new(callback), dispatch));
+        Ok(handle)
+    }
+    //The source has to stay open until it is deregistered
+    pub fn register<R, H>(
+        &mut self,
+        source: &R,
+        token: u64,
+        interest: Interest,
+        handler: H,
+    ) -> Result<(), Error>
+    where
+        R: AsRawFd + ?Sized,
+        H: Fn(usize, Readiness) -> Result<(), ThreadErr> + 'static + Send + Sync,
+    {
+        self.poller.register(source, token, interest)?;
+        self.sources.insert(token, Arc::new(handler));
+        Ok(())
+    }
+    pub fn deregister<R: AsRawFd + ?Sized>(&mut self, source: &R, token: u64) -> Result<(), Error> {
+        self.sources.remove(&token);
+        self.poller.deregister(source)
+    }
+    //register and deregister for other threads, including handlers while serve is running
+    pub fn registrar(&self) -> Registrar {
+        Registrar {
+            waker: self.poller.waker(),
+            registered: Arc::clone(&self.registered),
+        }
+    }
+    //Handlers are queued before their source, so taking them before every wait puts each one
+    //in place before its source can be reported ready
+    fn take_registered(&mut self) {
+        for (token, handler) in self.registered.lock().unwrap().drain(..) {
+            match handler {
+                Some(handler) => self.sources.insert(token, handler),
+                None => self.sources.remove(&token),
+            };
         }
     }
-    pub fn serve<F>(&mut self, timeout: i32, conn_closure: F)
+    //Handlers can use it to stop the server or queue work for the poll loop
+    pub fn waker(&self) -> Waker {
+        self.poller.waker()
+    }
+    //Has to be called before any other thread is spawned, see Poller::shutdown_on_signals
+    pub fn shutdown_on_signals(&mut self, signals: &[c_int]) -> Result<(), Error> {
+        self.poller.shutdown_on_signals(signals)
+    }
+    //How long in-flight tasks get to finish once a shutdown starts
+    pub fn set_shutdown_grace(&mut self, grace: Duration) {
+        self.shutdown_grace = grace;
+    }
+    pub fn serve<F>(&mut self, timeout: i32, conn_closure: F) -> Result<(), PollError>
+    where
+        F: Fn(usize, Connection) -> Result<(), ThreadErr> + 'static + Send + Sync,
+    {
+        self.serve_with_policy(timeout, conn_closure, ErrorPolicy::default_for)
+    }
+    //Answers every request decoded from a connection, responses go out in request order
+    pub fn serve_codec<C, S>(&mut self, timeout: i32, codec: C, service: S) -> Result<(), PollError>
     where
-        F: Fn(usize, Arc<Mutex<Connection>>) -> Result<(), ThreadErr> + 'static + Send + Sync,
+        C: Codec,
+        S: Fn(C::Req) -> C::Resp + 'static + Send + Sync,
     {
+        self.serve(timeout, move |_, conn| {
+            if !matches!(conn.state, ConnectionState::Data) {
+                return Ok(());
+            }
+            let mut out = BytesBuf::new();
+            let mut close = false;
+            while !close {
+                //Not held while the service runs so the poller can keep appending
+                let decoded = codec.decode(&mut conn.inbound());
+                let resp = match decoded {
+                    Ok(Some(req)) => service(req),
+                    Ok(None) => break,
+                    Err(err) => match codec.reject(err) {
+                        Ok(resp) => {
+                            close = true;
+                            resp
+                        }
+                        Err(err) => {
+                            //The poller reports the hangup as Closed
+                            let _ = conn.stream.lock().unwrap().shutdown(Shutdown::Both);
+                            return Err(Box::new(err));
+                        }
+                    },
+                };
+                close |= !codec.keep_open(&resp);
+                codec.encode(resp, &mut out);
+            }
+            if !out.is_empty() {
+                conn.send(&out).map_err(|err| Box::new(err) as ThreadErr)?;
+            }
+            if close {
+                conn.inbound().clear();
+                conn.finish().map_err(|err| Box::new(err) as ThreadErr)?;
+            }
+            Ok(())
+        })
+    }
+    //Requests that fail to parse get a 400, or a 501 when they need an unsupported feature such
+    //as a transfer-encoding other than chunked, and the connection is closed
+    pub fn serve_http(&mut self, timeout: i32, router: Router) -> Result<(), PollError> {
+        self.serve_codec(timeout, Http, move |request| {
+            let response = router.handle(&request);
+            Reply::new(&request, response)
+        })
+    }
+    pub fn serve_with_policy<F, P>(
+        &mut self,
+        timeout: i32,
+        conn_closure: F,
+        mut policy: P,
+    ) -> Result<(), PollError>
+    where
+        F: Fn(usize, Connection) -> Result<(), ThreadErr> + 'static + Send + Sync,
+        P: FnMut(&PollError) -> ErrorPolicy,
+    {
+        self.thread_pool.dispatch();
+        let mailboxes = Mailboxes::new(Arc::clone(&self.thread_pool), Arc::new(conn_closure));
+        let waker = self.poller.waker();
+        let served = loop {
+            self.take_registered();
+            let mut stopping = false;
+            let (sources, timers, datagrams) = (&self.sources, &mut self.timers, &self.datagrams);
+            let result = self.poller.poll(timeout, |event| {
+                if let Event::Shutdown = event {
+                    stopping = true;
+                } else {
+                    deliver(event, &mailboxes, sources, timers, datagrams);
+                }
+            });
+            if let Err(err) = result
+                && policy(&err) == ErrorPolicy::GiveUp
+            {
+                break Err(err);
+            }
+            if stopping || waker.is_triggered() {
+                break Ok(());
+            }
+        };
+        self.finish(&mailboxes);
+        served
+    }
+    //Stops accepting, gives queued work until the grace deadline, then closes everything
+    fn finish(&mut self, mailboxes: &Mailboxes) {
+        let deadline = Instant::now() + self.shutdown_grace;
         let pool = Arc::clone(&self.thread_pool);
-        pool.dispatch();
-        let closure = Arc::new(conn_closure);
-        loop {
-            let eq = Arc::clone(&self.thread_pool);
-            let closure = Arc::clone(&closure);
-            self.poller.poll(timeout, &self.server, move |conn| {
-                let conn = Arc::new(Mutex::new(conn));
-                let closure = Arc::clone(&closure);
-                let task: ThreadFunc = Arc::new(move |t_id| closure(t_id, Arc::clone(&conn)));
-                eq.enqueue(task);
+        if let Err(err) = self.poller.remove_listeners() {
+            println!("Could not remove listeners {err}");
+        }
+        while pool.pending() > 0 && Instant::now() < deadline {
+            self.take_registered();
+            let (sources, timers, datagrams) = (&self.sources, &mut self.timers, &self.datagrams);
+            let _ = self.poller.poll(10, |event| {
+                deliver(event, mailboxes, sources, timers, datagrams)
             });
         }
+        let (sources, timers, datagrams) = (&self.sources, &mut self.timers, &self.datagrams);
+        let closed = self
+            .poller
+            .close_all(|event| deliver(event, mailboxes, sources, timers, datagrams));
+        if let Err(err) = closed {
+            println!("Could not close connections {err}");
+        }
+        while pool.pending() > 0 && Instant::now() < deadline {
+            sleep(Duration::from_millis(1));
+        }
+        pool.shutdown(ShutdownMode::Abort);
+    }
+}
+
+//Adds sources and their handlers from any thread, serve picks them up on its next wakeup
+#[derive(Clone)]
+pub struct Registrar {
+    waker: Waker,
+    registered: Registered,
+}
+impl Registrar {
+    //Held until the poller adds it, after that the source has to stay open until it is deregistered
+    pub fn register<S, H>(
+        &self,
+        source: Arc<S>,
+        token: u64,
+        interest: Interest,
+        handler: H,
+    ) -> Result<(), Error>
+    where
+        S: AsRawFd + Send + Sync + 'static,
+        H: Fn(usize, Readiness) -> Result<(), ThreadErr> + 'static + Send + Sync,
+    {
+        Poller::check_token(token)?;
+        self.registered
+            .lock()
+            .unwrap()
+            .push((token, Some(Arc::new(handler))));
+        self.waker.register(source, token, interest)
+    }
+    pub fn deregister<R: AsRawFd + ?Sized>(&self, source: &R, token: u64) -> Result<(), Error> {
+        self.registered.lock().unwrap().push((token, None));
+        self.waker.deregister(source)
+    }
+}
+
+//Events for one connection id run one at a time, in the order they were polled
+#[derive(Clone)]
+struct Mailboxes {
+    pool: Arc<ThreadPool>,
+    closure: ConnFunc,
+    //A connection id has an entry while a task is scheduled to deliver its events
+    boxes: Arc<Mutex<HashMap<u64, VecDeque<Connection>>>>,
+}
+impl Mailboxes {
+    fn new(pool: Arc<ThreadPool>, closure: ConnFunc) -> Self {
+        Self {
+            pool,
+            closure,
+            boxes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    fn post(&self, conn: Connection) {
+        let id = conn.id;
+        let mut boxes = self.boxes.lock().unwrap();
+        if let Some(queue) = boxes.get_mut(&id) {
+            queue.push_back(conn);
+            return;
+        }
+        boxes.insert(id, VecDeque::from([conn]));
+        drop(boxes);
+        self.schedule(id);
+    }
+    //Each task delivers a single event so a busy connection does not hold on to a worker
+    fn schedule(&self, id: u64) {
+        let mailboxes = self.clone();
+        self.pool.enqueue(Box::new(move |t_id| {
+            let conn = mailboxes
+                .boxes
+                .lock()
+                .unwrap()
+                .get_mut(&id)
+                .and_then(VecDeque::pop_front);
+            let Some(conn) = conn else {
+                return Ok(());
+            };
+            let _next = NextDelivery(&mailboxes, id);
+            (mailboxes.closure)(t_id, conn)
+        }));
+    }
+    fn next(&self, id: u64) {
+        let mut boxes = self.boxes.lock().unwrap();
+        if boxes.get(&id).is_none_or(VecDeque::is_empty) {
+            boxes.remove(&id);
+            return;
+        }
+        drop(boxes);
+        self.schedule(id);
+    }
+}
+
+//Moves on to the next event even when the closure panics
+struct NextDelivery<'a>(&'a Mailboxes, u64);
+impl Drop for NextDelivery<'_> {
+    fn drop(&mut self) {
+        self.0.next(self.1);
+    }
+}
+
+fn deliver(
+    event: Event,
+    mailboxes: &Mailboxes,
+    sources: &HashMap<u64, ReadyFunc>,
+    timers: &mut HashMap<u64, (TimerFunc, TimerDispatch)>,
+    datagrams: &HashMap<usize, DatagramFunc>,
+) {
+    match event {
+        Event::Connection(conn) => mailboxes.post(conn),
+        Event::Ready(readiness) => {
+            if let Some(handler) = sources.get(&readiness.token) {
+                let handler = Arc::clone(handler);
+                mailboxes
+                    .pool
+                    .enqueue(Box::new(move |t_id| handler(t_id, readiness)));
+            }
+        }
+        Event::Datagram(datagram) => {
+            if let Some(handler) = datagrams.get(&datagram.socket_id) {
+                let handler = Arc::clone(handler);
+                mailboxes
+                    .pool
+                    .enqueue(Box::new(move |t_id| handler(t_id, datagram)));
+            }
+        }
+        Event::Timer(Expiry { id, count, done }) => {
+            let scheduled = if done {
+                timers.remove(&id)
+            } else {
+                timers.get(&id).cloned()
+            };
+            let Some((callback, dispatch)) = scheduled else {
+                return;
+            };
+            if count == 0 {
+                return;
+            }
+            match dispatch {
+                TimerDispatch::Inline => {
+                    if let Err(err) = callback() {
+                        println!("Timer {id:#x} failed {err}");
+                    }
+                }
+                TimerDispatch::Pool => mailboxes.pool.enqueue(Box::new(move |_| callback())),
+            }
+        }
+        Event::Shutdown => {}
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{
+        io::Write,
+        net::{SocketAddr, TcpStream},
+        sync::atomic::{AtomicBool, Ordering},
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+
+    pub(crate) fn test_server(workers: usize) -> AsyncListener {
+        AsyncListener::new("127.0.0.1:0", 20)
+            .with_thread_pool(ThreadPool::builder().workers(workers).build())
+    }
+    //A server running serve on its own thread
+    pub(crate) struct Running {
+        addr: Option<SocketAddr>,
+        waker: Waker,
+        serving: JoinHandle<Result<(), PollError>>,
+    }
+    impl Running {
+        pub(crate) fn addr(&self) -> SocketAddr {
+            self.addr.expect("Server has no TCP listener")
+        }
+        pub(crate) fn stop(self) {
+            self.waker.shutdown().unwrap();
+            self.join();
+        }
+        //For servers that are shut down by one of their own handlers
+        pub(crate) fn join(self) {
+            self.serving
+                .join()
+                .unwrap()
+                .expect("Server did not stop cleanly");
+        }
+    }
+    pub(crate) fn spawn_server<F>(mut server: AsyncListener, serve: F) -> Running
+    where
+        F: FnOnce(&mut AsyncListener) -> Result<(), PollError> + Send + 'static,
+    {
+        let addr = server
+            .listener(0)
+            .map(|listener| listener.local_addr().unwrap());
+        let waker = server.waker();
+        let serving = thread::spawn(move || serve(&mut server));
+        Running {
+            addr,
+            waker,
+            serving,
+        }
+    }
+
+    #[test]
+    fn shutdown_test() {
+        let mut server = test_server(2);
+        server.set_shutdown_grace(Duration::from_secs(1));
+        let closed = Arc::new(Mutex::new(0));
+
+        let counter = Arc::clone(&closed);
+        let running = spawn_server(server, move |server| {
+            server.serve(-1, move |_, conn| {
+                if let ConnectionState::Closed = conn.state {
+                    *counter.lock().unwrap() += 1;
+                }
+                Ok(())
+            })
+        });
+
+        let addr = running.addr();
+        let _stream = TcpStream::connect(addr).expect("Could not connect to test server");
+        sleep(Duration::from_millis(100));
+        running.stop();
+        assert_eq!(*closed.lock().unwrap(), 1, "Open connection was not closed");
+        TcpStream::connect(addr).expect_err("Listener still accepts");
+    }
+
+    #[test]
+    fn schedule_test() {
+        let mut server = test_server(2);
+        let handle = server.waker();
+        let ticks = Arc::new(Mutex::new(0));
+        let (once, cancelled) = (Arc::new(Mutex::new(0)), Arc::new(Mutex::new(0)));
+
+        let counter = Arc::clone(&ticks);
+        server
+            .schedule_every(
+                Duration::from_millis(20),
+                TimerDispatch::Inline,
+                move || {
+                    *counter.lock().unwrap() += 1;
+                    Ok(())
+                },
+            )
+            .unwrap();
+        let counter = Arc::clone(&once);
+        server
+            .schedule_after(Duration::from_millis(10), TimerDispatch::Pool, move || {
+                *counter.lock().unwrap() += 1;
+                Ok(())
+            })
+            .unwrap();
+        let counter = Arc::clone(&cancelled);
+        let doomed = server
+            .schedule_every(Duration::from_millis(50), TimerDispatch::Pool, move || {
+                *counter.lock().unwrap() += 1;
+                Ok(())
+            })
+            .unwrap();
+        server
+            .schedule_after(
+                Duration::from_millis(200),
+                TimerDispatch::Inline,
+                move || {
+                    handle.shutdown().unwrap();
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        let running = spawn_server(server, |server| server.serve(-1, |_, _| Ok(())));
+        doomed.cancel().unwrap();
+        running.join();
+
+        let ticks = *ticks.lock().unwrap();
+        println!("Periodic timer ran {ticks} times");
+        assert!(ticks >= 5, "Periodic timer ran {ticks} times");
+        assert_eq!(*once.lock().unwrap(), 1);
+        assert_eq!(*cancelled.lock().unwrap(), 0, "Cancelled timer ran");
+    }
+
+    #[test]
+    fn serve_codec_test() {
+        let running = spawn_server(test_server(2), |server| {
+            server.serve_codec(-1, codec::Line::default(), |line: String| {
+                line.to_uppercase()
+            })
+        });
+
+        let mut stream =
+            TcpStream::connect(running.addr()).expect("Could not connect to test server");
+        //Split mid-line so the second request arrives over two events
+        stream.write_all(b"first\nsec").unwrap();
+        sleep(Duration::from_millis(20));
+        stream.write_all(b"ond\nthird\n").unwrap();
+        let mut reader = std::io::BufReader::new(stream);
+        let mut replies = Vec::new();
+        for _ in 0..3 {
+            let mut line = String::new();
+            std::io::BufRead::read_line(&mut reader, &mut line).unwrap();
+            replies.push(line);
+        }
+        println!("Replies {replies:?}");
+        assert_eq!(replies, vec!["FIRST\n", "SECOND\n", "THIRD\n"]);
+        running.stop();
+    }
+
+    #[test]
+    fn udp_echo_test() {
+        let mut server = test_server(2);
+        let id = server
+            .add_udp("127.0.0.1:0", |_, datagram| {
+                datagram.reply(&datagram.bytes).unwrap();
+                Ok(())
+            })
+            .unwrap();
+        let addr = server.udp_socket(id).unwrap().local_addr().unwrap();
+        let running = spawn_server(server, |server| server.serve(-1, |_, _| Ok(())));
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.send_to(b"marco", addr).unwrap();
+        let mut buff = [0; 16];
+        let (size, from) = client.recv_from(&mut buff).unwrap();
+        assert_eq!((&buff[..size], from), (&b"marco"[..], addr));
+        running.stop();
+    }
+
+    #[test]
+    fn unix_socket_test() {
+        let path = std::env::temp_dir().join(format!("rust_epoll_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let addr = UnixSocketAddr::from_pathname(&path).unwrap();
+        let server = AsyncListener::new_unix(&addr, 20)
+            .expect("Could not bind unix socket")
+            .with_thread_pool(ThreadPool::builder().workers(2).build());
+        let running = spawn_server(server, |server| {
+            server.serve_codec(-1, codec::Line::default(), |line: String| {
+                format!("echo {line}")
+            })
+        });
+
+        let stream = std::os::unix::net::UnixStream::connect(&path)
+            .expect("Could not connect to test server");
+        (&stream).write_all(b"over unix\n").unwrap();
+        let mut reply = String::new();
+        std::io::BufRead::read_line(&mut std::io::BufReader::new(&stream), &mut reply).unwrap();
+        assert_eq!(reply, "echo over unix\n");
+        running.stop();
+        //The socket file is left behind, so binding the path again fails instead of panicking
+        let rebound = AsyncListener::new_unix(&addr, 20);
+        assert_eq!(
+            rebound.err().map(|err| err.kind()),
+            Some(std::io::ErrorKind::AddrInUse)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ordered_delivery_test() {
+        let server = test_server(4);
+        let handle = server.waker();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let busy = Arc::new(AtomicBool::new(false));
+        //Panics in handlers are caught by the pool, so overlaps are checked afterwards
+        let overlapped = Arc::new(AtomicBool::new(false));
+
+        let events = Arc::clone(&seen);
+        let overlap = Arc::clone(&overlapped);
+        let running = spawn_server(server, move |server| {
+            server.serve(-1, move |_, conn| {
+                if busy.swap(true, Ordering::SeqCst) {
+                    overlap.store(true, Ordering::SeqCst);
+                }
+                println!("Event {:?}", conn.state);
+                let closed = matches!(conn.state, ConnectionState::Closed);
+                events.lock().unwrap().push(conn.state);
+                sleep(Duration::from_millis(10));
+                busy.store(false, Ordering::SeqCst);
+                if closed {
+                    handle.shutdown().unwrap();
+                }
+                Ok(())
+            })
+        });
+
+        let mut stream =
+            TcpStream::connect(running.addr()).expect("Could not connect to test server");
+        for _ in 0..5 {
+            stream.write_all(b"ping").unwrap();
+            sleep(Duration::from_millis(2));
+        }
+        drop(stream);
+        running.join();
+        let seen = seen.lock().unwrap();
+        println!("Events {seen:?}");
+        assert!(
+            !overlapped.load(Ordering::SeqCst),
+            "Two events for one connection ran at once"
+        );
+        assert!(matches!(seen.first(), Some(ConnectionState::Opened)));
+        assert!(matches!(seen.last(), Some(ConnectionState::Closed)));
+        assert!(seen.len() > 2, "No data events were delivered");
+    }
+
+    #[test]
+    fn registrar_test() {
+        let server = test_server(2);
+        let registrar = server.registrar();
+        let running = spawn_server(server, |server| server.serve(-1, |_, _| Ok(())));
+
+        //Added while serve is running, from another thread
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (mut writer, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+        let reader = Arc::new(reader);
+        registrar
+            .register(
+                Arc::clone(&reader),
+                9,
+                Interest::READABLE,
+                move |_, readiness| {
+                    let _ = tx.send(readiness.token);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        writer.write_all(b"x").unwrap();
+        let token = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Handler never ran");
+        assert_eq!(token, 9);
+
+        registrar.deregister(&*reader, 9).unwrap();
+        running.stop();
     }
 }
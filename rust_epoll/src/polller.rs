@@ -1,25 +1,383 @@
-use libc::{self, EPOLLERR, EPOLLET, EPOLLHUP, EPOLLRDHUP, c_int, epoll_event};
-use std::alloc::{self, Layout};
+use libc::{EPOLLERR, EPOLLET, EPOLLHUP, EPOLLRDHUP, c_int, epoll_event};
+use std::collections::{HashSet, VecDeque};
 use std::ffi::c_uint;
-use std::io::{Error, ErrorKind, Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::fmt::{self, Display};
+use std::io::{Error, ErrorKind, Write};
+use std::mem;
+use std::net::{
+    Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream,
+    UdpSocket,
+};
+use std::ops::{BitOr, Deref, DerefMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::ptr::null_mut;
-use std::sync::{Arc, Mutex};
-use std::thread::{self};
-use std::{net, os::fd::AsRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+use crate::bytes::BytesBuf;
+use crate::framer::Framer;
+use crate::slab::Slab;
+use crate::stream::{Addr, ListenSocket, PeerCred, Stream};
+use crate::timer::{TimerFd, TimerWheel};
+use crate::udp::{self, RecvBatch};
+
+//Tokens at or above USER_TOKEN_LIMIT are reserved for the poller itself
+pub const USER_TOKEN_LIMIT: u64 = 1 << 63;
+const TAG_MASK: u64 = 0xFF << 56;
+const LISTENER_TAG: u64 = 0x80 << 56;
+const CONNECTION_TAG: u64 = 0x81 << 56;
+const WAKER_TOKEN: u64 = 0x82 << 56;
+const SIGNAL_TOKEN: u64 = 0x83 << 56;
+const TIMER_TOKEN: u64 = 0x84 << 56;
+const SCHEDULE_TAG: u64 = 0x85 << 56;
+const UDP_TAG: u64 = 0x86 << 56;
+const ACCEPT_TOKEN: u64 = 0x87 << 56;
+const TIMER_SLOTS: usize = 512;
+//How long listeners stay disarmed after accept ran out of fds, unless a connection closes first
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+//Bytes taken from one connection per wakeup, so a fast sender can not starve the other fds
+const READ_BUDGET: usize = 64 * 1024;
+//Fits the largest default LengthPrefixed frame and the largest request the http module accepts
+const DEFAULT_INBOUND_LIMIT: usize = 17 * 1024 * 1024;
+//Connection ids keep the slot index in the low bits and the slot's generation above it
+const INDEX_BITS: u32 = 32;
+const GENERATION_MASK: u32 = (1 << 24) - 1;
+
+fn pack_id(index: usize, generation: u32) -> u64 {
+    (u64::from(generation) << INDEX_BITS) | u64::try_from(index).unwrap()
+}
+fn unpack_id(id: u64) -> (usize, u32) {
+    let index = usize::try_from(id & ((1 << INDEX_BITS) - 1)).unwrap();
+    let generation = u32::try_from(id >> INDEX_BITS).unwrap() & GENERATION_MASK;
+    (index, generation)
+}
+
+#[derive(Debug)]
+pub enum PollError {
+    Interrupted,
+    TooManyFiles(Error),
+    //A token the poller never handed out, late events for closed connections are dropped instead
+    StaleEvent(u64),
+    Io(Error),
+}
+impl Display for PollError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PollError::Interrupted => write!(f, "wait was interrupted by a signal"),
+            PollError::TooManyFiles(err) => write!(f, "out of file descriptors: {err}"),
+            PollError::StaleEvent(token) => write!(f, "event for unknown token {token:#x}"),
+            PollError::Io(err) => write!(f, "poll failed: {err}"),
+        }
+    }
+}
+impl std::error::Error for PollError {}
+impl From<Error> for PollError {
+    fn from(err: Error) -> Self {
+        match err.raw_os_error() {
+            Some(libc::EINTR) => PollError::Interrupted,
+            Some(libc::EMFILE) | Some(libc::ENFILE) => PollError::TooManyFiles(err),
+            _ => PollError::Io(err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interest(u32);
+impl Interest {
+    pub const READABLE: Interest = Interest(libc::EPOLLIN as u32);
+    pub const WRITABLE: Interest = Interest(libc::EPOLLOUT as u32);
+    pub const EDGE: Interest = Interest(EPOLLET as u32);
+
+    fn events(self) -> u32 {
+        self.0 | (EPOLLHUP | EPOLLRDHUP | EPOLLERR) as u32
+    }
+}
+impl BitOr for Interest {
+    type Output = Interest;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Interest(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Readiness {
+    pub token: u64,
+    pub readable: bool,
+    pub writable: bool,
+    pub hangup: bool,
+    pub error: bool,
+}
+impl Readiness {
+    fn from_event(event: &epoll_event) -> Self {
+        Self {
+            token: event.u64,
+            readable: event.events & libc::EPOLLIN as u32 != 0,
+            writable: event.events & libc::EPOLLOUT as u32 != 0,
+            hangup: event.events & (EPOLLHUP | EPOLLRDHUP) as u32 != 0,
+            error: event.events & EPOLLERR as u32 != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Expiry {
+    pub id: u64,
+    //Expirations since the last event, zero when the timer was cancelled
+    pub count: u64,
+    //The poller dropped the timer, it will not fire again
+    pub done: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    pub from: SocketAddr,
+    pub bytes: Vec<u8>,
+    //Id returned by add_udp
+    pub socket_id: usize,
+    pub socket: Arc<UdpSocket>,
+}
+impl Datagram {
+    pub fn reply(&self, bytes: &[u8]) -> Result<(), Error> {
+        self.socket.send_to(bytes, self.from).map(|_| ())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Connection(Connection),
+    Ready(Readiness),
+    Datagram(Datagram),
+    Timer(Expiry),
+    Shutdown,
+}
+
+#[derive(Debug)]
+struct EventFd {
+    fd: OwnedFd,
+}
+impl EventFd {
+    fn new() -> Result<Self, Error> {
+        unsafe {
+            let fd = libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC);
+            if fd == -1 {
+                return Err(Error::last_os_error());
+            }
+            Ok(Self {
+                fd: OwnedFd::from_raw_fd(fd),
+            })
+        }
+    }
+    fn notify(&self) -> Result<(), Error> {
+        let value: u64 = 1;
+        let written = unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                &value as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+        if written == -1 {
+            let err = Error::last_os_error();
+            //The counter is saturated so the reader is going to wake up anyway
+            if err.kind() != ErrorKind::WouldBlock {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+    fn drain(&self) {
+        let mut value: u64 = 0;
+        unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                &mut value as *mut u64 as *mut libc::c_void,
+                mem::size_of::<u64>(),
+            );
+        }
+    }
+}
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+//A source handed over by another thread, held until the poller has added it so its fd can
+//not be closed and reused in the meantime
+struct SharedSource(Arc<dyn AsRawFd + Send + Sync>);
+impl fmt::Debug for SharedSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedSource({})", self.0.as_raw_fd())
+    }
+}
+
+//Work the poller does on behalf of other threads, in the order it was queued
+#[derive(Debug)]
+enum Command {
+    Register {
+        source: SharedSource,
+        token: u64,
+        interest: Interest,
+    },
+    Deregister(RawFd),
+    Send(u64, Vec<u8>),
+    Shutdown,
+}
+
+#[derive(Debug)]
+struct WakerInner {
+    event: EventFd,
+    commands: Mutex<VecDeque<Command>>,
+    triggered: AtomicBool,
+}
+
+//Interrupts the poller's wait from any thread, clones all reach the same poller
+#[derive(Debug, Clone)]
+pub struct Waker {
+    inner: Arc<WakerInner>,
+}
+impl Waker {
+    fn new() -> Result<Self, Error> {
+        Ok(Self {
+            inner: Arc::new(WakerInner {
+                event: EventFd::new()?,
+                commands: Mutex::new(VecDeque::new()),
+                triggered: AtomicBool::new(false),
+            }),
+        })
+    }
+    //Makes the current or next poll return without doing anything else
+    pub fn wake(&self) -> Result<(), Error> {
+        self.inner.event.notify()
+    }
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.inner.triggered.store(true, Ordering::SeqCst);
+        self.push(Command::Shutdown)
+    }
+    pub fn is_triggered(&self) -> bool {
+        self.inner.triggered.load(Ordering::SeqCst)
+    }
+    //Same as Poller::register, done on the poller's next wakeup. The waker keeps the source open
+    //until then, after that it has to stay open until it is deregistered.
+    //Under AsyncListener use its Registrar, events for sources added here have no handler
+    pub fn register<S>(&self, source: Arc<S>, token: u64, interest: Interest) -> Result<(), Error>
+    where
+        S: AsRawFd + Send + Sync + 'static,
+    {
+        Poller::check_token(token)?;
+        self.push(Command::Register {
+            source: SharedSource(source),
+            token,
+            interest,
+        })
+    }
+    pub fn deregister<S: AsRawFd + ?Sized>(&self, source: &S) -> Result<(), Error> {
+        self.push(Command::Deregister(source.as_raw_fd()))
+    }
+    //Sends through the connection with this id from the poller's thread, dropped if it has closed.
+    //A failed send closes that connection
+    pub fn send(&self, id: u64, data: Vec<u8>) -> Result<(), Error> {
+        self.push(Command::Send(id, data))
+    }
+    fn push(&self, command: Command) -> Result<(), Error> {
+        self.inner.commands.lock().unwrap().push_back(command);
+        self.inner.event.notify()
+    }
+    fn take_commands(&self) -> VecDeque<Command> {
+        self.inner.event.drain();
+        mem::take(&mut *self.inner.commands.lock().unwrap())
+    }
+}
+
+//Connections that stay quiet longer than this are reported as TimedOut and closed, None turns a check off
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    //Nothing was read or written
+    pub idle: Option<Duration>,
+    //Nothing was read
+    pub read: Option<Duration>,
+    //Buffered output made no progress
+    pub write: Option<Duration>,
+}
+impl Timeouts {
+    fn shortest(&self) -> Option<Duration> {
+        [self.idle, self.read, self.write]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+}
+
+//Cancels a timer from any thread, the poller drops it on its next wakeup
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    id: u64,
+    cancelled: Arc<AtomicBool>,
+    timer: Arc<TimerFd>,
+}
+impl TimerHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    pub fn cancel(&self) -> Result<(), Error> {
+        self.cancelled.store(true, Ordering::SeqCst);
+        //Fires right away so the poller notices without waiting out the interval
+        self.timer.set(Duration::from_nanos(1), Duration::ZERO)
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+struct Scheduled {
+    timer: Arc<TimerFd>,
+    cancelled: Arc<AtomicBool>,
+    repeat: bool,
+}
 
 #[derive(Debug, Clone)]
 pub enum ConnectionState {
     Closed,
     Opened,
     Data,
+    Writable,
+    //Closed by the poller because a timeout ran out, the socket is already shut down
+    TimedOut,
+}
+#[derive(Debug)]
+struct Outbound {
+    data: Vec<u8>,
+    //Last time bytes reached the socket
+    written: Instant,
+    //Set while data is buffered, moves forward whenever a flush makes progress
+    stalled: Option<Instant>,
+    //Shut down the write side once data is empty
+    finish: bool,
+    //The peer shut down its side, EPOLLIN is not armed again
+    read_closed: bool,
+    //inbound is full, EPOLLIN stays off until a handler takes data out
+    paused: bool,
 }
 #[derive(Debug)]
 pub struct Connection {
     pub state: ConnectionState,
-    pub stream: Arc<Mutex<TcpStream>>,
-    pub socket_addr: SocketAddr,
+    pub stream: Arc<Mutex<Stream>>,
+    pub socket_addr: Addr,
+    //Only set for Unix sockets
+    pub peer_cred: Option<PeerCred>,
+    //Not handed out again until the slot has been reused 2^24 times
     pub id: u64,
+    pub listener: usize,
+    //Everything read so far that the handlers have not consumed, the poller only appends
+    inbound: Arc<Mutex<BytesBuf>>,
+    inbound_limit: usize,
+    outbound: Arc<Mutex<Outbound>>,
+    //Only the poller's copy is kept up to date
+    read: Instant,
+    selector: Arc<Selector>,
+    //Declared last so it is dropped after outbound
+    release: Release,
 }
 impl Clone for Connection {
     fn clone(&self) -> Self {
@@ -27,184 +385,305 @@ impl Clone for Connection {
         Self {
             state: self.state.clone(),
             stream,
-            socket_addr: self.socket_addr,
+            socket_addr: self.socket_addr.clone(),
+            peer_cred: self.peer_cred,
             id: self.id,
+            listener: self.listener,
+            inbound: Arc::clone(&self.inbound),
+            inbound_limit: self.inbound_limit,
+            outbound: Arc::clone(&self.outbound),
+            read: self.read,
+            selector: Arc::clone(&self.selector),
+            release: self.release.clone(),
         }
     }
 }
 
-pub struct Poller {
-    epollfd: c_uint,
-    max_events: c_uint,
-    connections: Vec<Option<Connection>>,
+//Wakes the poller when a copy of a half-closed connection is dropped, so it can free the slot
+//once no handler can send through it anymore
+#[derive(Debug, Clone)]
+struct Release {
+    outbound: Weak<Mutex<Outbound>>,
+    waker: Waker,
 }
-impl Poller {
-    pub fn new(max_events: u32, listener: &net::TcpListener) -> Result<Poller, Error> {
-        unsafe {
-            let epollfd = libc::epoll_create(1);
-            if epollfd == -1 {
-                return Err(Error::last_os_error());
-            };
-
-            let mut events = epoll_event {
-                u64: 0,
-                events: u32::try_from(libc::EPOLLIN | libc::EPOLLOUT).unwrap(),
-            };
-
-            let ptr: *mut epoll_event = &mut events;
-
-            listener
-                .set_nonblocking(true)
-                .expect("Coud not set listener to non-blocking");
-            let err = libc::epoll_ctl(epollfd, libc::EPOLL_CTL_ADD, listener.as_raw_fd(), ptr);
-            if err == -1 {
-                return Err(Error::last_os_error());
-            };
-
-            Ok(Poller {
-                epollfd: u32::try_from(epollfd).unwrap(),
-                max_events,
-                connections: Vec::new(),
-            })
+impl Drop for Release {
+    fn drop(&mut self) {
+        let Some(outbound) = self.outbound.upgrade() else {
+            return;
+        };
+        let read_closed = outbound.lock().is_ok_and(|outbound| outbound.read_closed);
+        drop(outbound);
+        if read_closed {
+            let _ = self.waker.wake();
         }
     }
-    pub fn poll<F>(&mut self, timeout: i32, listener: &TcpListener, mut connection_closure: F)
-    where
-        F: FnMut(Connection),
-    {
-        let events = self.wait(timeout).expect("Could not get events");
-
-        for event in events {
-            if event.u64 == 0 {
-                let mut conn: Connection = match listener.accept() {
-                    Ok((stream, socket_addr)) => Connection {
-                        id: 0,
-                        socket_addr,
-                        stream: Arc::new(Mutex::new(stream)),
-                        state: ConnectionState::Opened,
-                    },
-                    Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-                    Err(err) if err.raw_os_error() == Some(24) => {
-                        continue;
-                    }
-                    Err(err) => panic!("Could Not Poll {err:?}"),
-                };
-
-                let id: u64;
-
-                if let Some((index, slot)) = self
-                    .connections
-                    .iter_mut()
-                    .enumerate()
-                    .find(|(_, slot)| slot.is_none())
-                {
-                    conn.id = u64::try_from(index).unwrap();
-                    id = conn.id;
-                    *slot = Some(conn);
-                } else {
-                    conn.id = u64::try_from(self.connections.len()).unwrap();
-                    id = conn.id;
-                    self.connections.push(Some(conn));
-                }
+}
+impl Connection {
+    //Everything read so far that the handlers have not consumed. The poller stops reading once
+    //it holds the inbound limit, and starts again when the guard is dropped with less
+    pub fn inbound(&self) -> InboundGuard<'_> {
+        InboundGuard {
+            buf: self.inbound.lock().unwrap(),
+            conn: self,
+        }
+    }
+    //Takes the next complete message out of inbound, None until more data arrives
+    pub fn next_frame<F: Framer + ?Sized>(&self, framer: &F) -> Result<Option<Vec<u8>>, Error> {
+        framer.next_frame(&mut self.inbound())
+    }
+    //Writes what the socket takes right away and buffers the rest until the poller sees EPOLLOUT
+    pub fn send(&self, data: &[u8]) -> Result<(), Error> {
+        let mut outbound = self.outbound.lock().unwrap();
+        if !outbound.data.is_empty() {
+            outbound.data.extend_from_slice(data);
+            return Ok(());
+        }
+        let mut stream = self.stream.lock().unwrap();
+        let mut written = 0;
+        while written < data.len() {
+            match stream.write(&data[written..]) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(size) => written += size,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        let now = Instant::now();
+        if written > 0 {
+            outbound.written = now;
+        }
+        if written < data.len() {
+            outbound.data.extend_from_slice(&data[written..]);
+            outbound.stalled = Some(now);
+            self.arm(&outbound, stream.as_raw_fd())?;
+        }
+        Ok(())
+    }
+    pub fn pending(&self) -> usize {
+        self.outbound.lock().unwrap().data.len()
+    }
+    //Pushes out buffered bytes, returns true once nothing is left to send
+    fn flush(&self) -> Result<bool, Error> {
+        let mut outbound = self.outbound.lock().unwrap();
+        if outbound.data.is_empty() {
+            return Ok(true);
+        }
+        let mut stream = self.stream.lock().unwrap();
+        let mut written = 0;
+        let result = loop {
+            if written == outbound.data.len() {
+                break Ok(());
+            }
+            match stream.write(&outbound.data[written..]) {
+                Ok(0) => break Err(Error::from(ErrorKind::WriteZero)),
+                Ok(size) => written += size,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => break Err(err),
+            }
+        };
+        if let Err(err) = result {
+            outbound.data.clear();
+            outbound.stalled = None;
+            return Err(err);
+        }
+        if written > 0 {
+            let now = Instant::now();
+            outbound.written = now;
+            outbound.stalled = Some(now);
+        }
+        outbound.data.drain(..written);
+        if outbound.data.is_empty() {
+            outbound.stalled = None;
+            self.arm(&outbound, stream.as_raw_fd())?;
+            if outbound.finish {
+                stream.shutdown(Shutdown::Write)?;
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+    //Shuts down the write side after everything sent so far has gone out, the peer sees EOF.
+    //The connection is reported as Closed once the peer closes its side
+    pub fn finish(&self) -> Result<(), Error> {
+        let mut outbound = self.outbound.lock().unwrap();
+        if outbound.data.is_empty() {
+            return self.stream.lock().unwrap().shutdown(Shutdown::Write);
+        }
+        outbound.finish = true;
+        Ok(())
+    }
+    //Earliest moment one of the timeouts runs out
+    fn deadline(&self, timeouts: &Timeouts) -> Option<Instant> {
+        let outbound = self.outbound.lock().unwrap();
+        let idle = timeouts
+            .idle
+            .map(|timeout| self.read.max(outbound.written) + timeout);
+        //Nothing more is coming from a peer that shut down its side
+        let read = timeouts
+            .read
+            .filter(|_| !outbound.read_closed)
+            .map(|timeout| self.read + timeout);
+        let write = timeouts
+            .write
+            .zip(outbound.stalled)
+            .map(|(timeout, since)| since + timeout);
+        [idle, read, write].into_iter().flatten().min()
+    }
+    //Called by the poller with what it read. Reading stops once inbound holds the limit, a read
+    //cut short by the budget re-arms so epoll reports the rest on a later wakeup
+    fn store_read(&self, bytes: &[u8], more: bool) -> Result<(), Error> {
+        let mut inbound = self.inbound.lock().unwrap();
+        inbound.extend_from_slice(bytes);
+        //Decided with inbound held, so a handler emptying it right now still sees paused
+        let full = inbound.len() >= self.inbound_limit;
+        if !full && !more {
+            return Ok(());
+        }
+        let mut outbound = self.outbound.lock().unwrap();
+        outbound.paused = full;
+        self.arm(&outbound, self.stream.lock().unwrap().as_raw_fd())
+    }
+    fn resume(&self) -> Result<(), Error> {
+        let mut outbound = self.outbound.lock().unwrap();
+        if !outbound.paused {
+            return Ok(());
+        }
+        outbound.paused = false;
+        self.arm(&outbound, self.stream.lock().unwrap().as_raw_fd())
+    }
+    //Takes the outbound guard so arming and disarming can not race. EPOLLOUT is armed while data
+    //is buffered, EPOLLIN and EPOLLRDHUP while inbound has room and the peer has not shut down its side
+    fn arm(&self, outbound: &Outbound, fd: c_int) -> Result<(), Error> {
+        let mut events = (EPOLLET | EPOLLHUP | EPOLLERR) as u32;
+        if !outbound.read_closed && !outbound.paused {
+            events |= (libc::EPOLLIN | EPOLLRDHUP) as u32;
+        }
+        if !outbound.data.is_empty() {
+            events |= libc::EPOLLOUT as u32;
+        }
+        self.selector
+            .ctl(libc::EPOLL_CTL_MOD, fd, CONNECTION_TAG | self.id, events)
+    }
+}
 
-                self.add_connection(
-                    self.connections
-                        .get(usize::try_from(id).unwrap())
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .stream
-                        .lock()
-                        .unwrap()
-                        .as_raw_fd(),
-                    id,
-                )
-                .unwrap();
-                let conn = self
-                    .connections
-                    .get_mut(usize::try_from(id).unwrap())
-                    .expect("Index is not the id of the connection")
-                    .as_ref()
-                    .expect("id should be valid at this point");
-                conn.stream
-                    .lock()
-                    .unwrap()
-                    .set_nonblocking(true)
-                    .expect("Unable to set new connection to non-blocking");
-                connection_closure(conn.clone());
-            } else if (event.events & libc::EPOLLIN as u32) != 0 {
-                let id = event.u64;
-                let conn = self
-                    .connections
-                    .get_mut(usize::try_from(id).unwrap())
-                    .expect("Invalid Id for connection")
-                    .as_mut()
-                    .unwrap();
-                conn.state = ConnectionState::Data;
-                connection_closure(conn.clone());
-            }
-            if event.events & (libc::EPOLLHUP | libc::EPOLLRDHUP | libc::EPOLLERR) as u32 != 0 {
-                let id = usize::try_from(event.u64).unwrap();
-                match self
-                    .connections
-                    .get_mut(id)
-                    .expect("Invalid Id for connection")
-                    .as_mut()
-                {
-                    Some(conn) => {
-                        conn.state = ConnectionState::Closed;
-                    }
-                    None => panic!("Connection doe not exsit"),
-                }
-                let conn_slot = self.connections.get(id).unwrap().as_ref();
-                self.delete_connection(conn_slot.unwrap().stream.lock().unwrap().as_raw_fd())
-                    .unwrap();
+//Locked view of a connection's unread bytes
+pub struct InboundGuard<'a> {
+    buf: MutexGuard<'a, BytesBuf>,
+    conn: &'a Connection,
+}
+impl Deref for InboundGuard<'_> {
+    type Target = BytesBuf;
+    fn deref(&self) -> &BytesBuf {
+        &self.buf
+    }
+}
+impl DerefMut for InboundGuard<'_> {
+    fn deref_mut(&mut self) -> &mut BytesBuf {
+        &mut self.buf
+    }
+}
+impl Drop for InboundGuard<'_> {
+    fn drop(&mut self) {
+        //Fails once the poller has dropped the connection, nothing is read then anyway
+        if self.buf.len() < self.conn.inbound_limit {
+            let _ = self.conn.resume();
+        }
+    }
+}
 
-                let conn = self.connections.get_mut(id).unwrap().take().unwrap();
-                connection_closure(conn);
-            }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerMode {
+    Level,
+    //Accepts until the backlog is empty on every wakeup
+    Edge,
+}
+impl ListenerMode {
+    fn events(self) -> u32 {
+        match self {
+            ListenerMode::Level => (libc::EPOLLIN | libc::EPOLLOUT) as u32,
+            ListenerMode::Edge => (libc::EPOLLIN | EPOLLET) as u32,
         }
     }
-    fn wait(&self, timeout: i32) -> Result<Vec<epoll_event>, Error> {
-        unsafe {
-            let max_events = usize::try_from(self.max_events).unwrap();
-            let layout = Layout::array::<epoll_event>(max_events).unwrap();
-            let events = alloc::alloc(layout) as *mut epoll_event;
+}
+struct Listener {
+    socket: ListenSocket,
+    mode: ListenerMode,
+}
 
-            //Blocks process
-            let size = libc::epoll_wait(
-                i32::try_from(self.epollfd).unwrap(),
-                events,
-                i32::try_from(self.max_events).unwrap(),
-                timeout,
-            );
-            if size == -1 {
-                return Err(Error::last_os_error());
+//Returns None once the backlog is empty, accepted sockets are already non-blocking
+fn accept4(fd: c_int) -> Result<Option<(Stream, Addr)>, Error> {
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let mut len = libc::socklen_t::try_from(mem::size_of::<libc::sockaddr_storage>()).unwrap();
+        let conn_fd = libc::accept4(
+            fd,
+            &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+            libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+        );
+        if conn_fd == -1 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::WouldBlock {
+                return Ok(None);
             }
-            let size = usize::try_from(size).unwrap();
-            Ok(Vec::from_raw_parts(events, size, max_events))
+            return Err(err);
+        }
+        if c_int::from(storage.ss_family) == libc::AF_UNIX {
+            let stream = UnixStream::from_raw_fd(conn_fd);
+            let addr = stream.peer_addr()?;
+            return Ok(Some((Stream::Unix(stream), Addr::Unix(addr))));
         }
+        let stream = TcpStream::from_raw_fd(conn_fd);
+        let socket_addr = socket_addr_from(&storage)?;
+        Ok(Some((Stream::Tcp(stream), Addr::Inet(socket_addr))))
     }
-    fn add_connection(&self, fd: c_int, id: u64) -> Result<(), Error> {
+}
+pub(crate) fn socket_addr_from(storage: &libc::sockaddr_storage) -> Result<SocketAddr, Error> {
+    match c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                ip,
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "unsupported address family",
+        )),
+    }
+}
+
+#[derive(Debug)]
+struct Selector {
+    epollfd: c_uint,
+}
+impl Selector {
+    fn ctl(&self, op: c_int, fd: c_int, token: u64, events: u32) -> Result<(), Error> {
         unsafe {
-            let mut event = epoll_event {
-                u64: id,
-                events: (libc::EPOLLIN | EPOLLET | EPOLLHUP | EPOLLRDHUP | EPOLLERR) as u32,
-            };
+            let mut event = epoll_event { u64: token, events };
             let ptr: *mut epoll_event = &mut event;
-            let err = libc::epoll_ctl(
-                i32::try_from(self.epollfd).unwrap(),
-                libc::EPOLL_CTL_ADD,
-                fd,
-                ptr,
-            );
+            let err = libc::epoll_ctl(i32::try_from(self.epollfd).unwrap(), op, fd, ptr);
             if err == -1 {
                 return Err(Error::last_os_error());
             }
         }
         Ok(())
     }
-    fn delete_connection(&self, fd: c_int) -> Result<(), Error> {
+    fn delete(&self, fd: c_int) -> Result<(), Error> {
         unsafe {
             let err = libc::epoll_ctl(
                 i32::try_from(self.epollfd).unwrap(),
@@ -219,7 +698,7 @@ impl Poller {
         Ok(())
     }
 }
-impl Drop for Poller {
+impl Drop for Selector {
     fn drop(&mut self) {
         unsafe {
             libc::close(i32::try_from(self.epollfd).unwrap());
@@ -227,52 +706,1628 @@ impl Drop for Poller {
     }
 }
 
-//Make request to poller
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    fn poller_test() {
-        let listener = TcpListener::bind("localhost:8080").unwrap();
-        let mut poller = Poller::new(20, &listener).expect("Did not create poller");
-
-        let (mut closed, mut opened, mut data) = (false, false, false);
+pub struct Poller {
+    selector: Arc<Selector>,
+    max_events: c_uint,
+    //Filled by every wait, allocated once and only replaced by set_max_events
+    events: Vec<epoll_event>,
+    listeners: Vec<Option<Listener>>,
+    udp_sockets: Vec<Option<Arc<UdpSocket>>>,
+    //Created with the first UDP socket, it holds a few MB of receive buffers
+    recv_batch: Option<Box<RecvBatch>>,
+    connections: Slab<Connection>,
+    //Reported as Closed after the peer shut down its side, kept registered while handlers
+    //still hold them so what they send goes out
+    closing: HashSet<u64>,
+    waker: Waker,
+    signals: Option<OwnedFd>,
+    timeouts: Timeouts,
+    //Created by the first set_timeouts, ticks the wheel while any timeout is set
+    timer: Option<TimerFd>,
+    //Keyed by connection id, entries for closed connections are dropped when they come due
+    wheel: TimerWheel<u64>,
+    scheduled: Slab<Scheduled>,
+    inbound_limit: usize,
+    //Created with the first listener, since there may be no fd left to create it when it is needed
+    accept_timer: Option<TimerFd>,
+    //Connection count when accept ran out of fds, None while the listeners are armed
+    accept_paused: Option<usize>,
+    //Reads land here first so the poller never waits on a handler holding inbound
+    scratch: BytesBuf,
+}
+impl Poller {
+    pub fn new(max_events: u32) -> Result<Poller, Error> {
+        let max_events = max_events.max(1);
+        let selector = unsafe {
+            let epollfd = libc::epoll_create(1);
+            if epollfd == -1 {
+                return Err(Error::last_os_error());
+            };
+            Arc::new(Selector {
+                epollfd: u32::try_from(epollfd).unwrap(),
+            })
+        };
+        let waker = Waker::new()?;
+        selector.ctl(
+            libc::EPOLL_CTL_ADD,
+            waker.inner.event.as_raw_fd(),
+            WAKER_TOKEN,
+            Interest::READABLE.events(),
+        )?;
 
-        thread::spawn(|| {
-            for _ in 0..5 {
-                let mut stream =
-                    TcpStream::connect("localhost:8080").expect("Could not connect to test server");
-                stream
-                    .write_all("Blah".as_bytes())
-                    .expect("Error sending response");
+        Ok(Poller {
+            selector,
+            max_events,
+            events: Vec::with_capacity(usize::try_from(max_events).unwrap()),
+            listeners: Vec::new(),
+            udp_sockets: Vec::new(),
+            recv_batch: None,
+            connections: Slab::new(),
+            closing: HashSet::new(),
+            waker,
+            signals: None,
+            timeouts: Timeouts::default(),
+            timer: None,
+            wheel: TimerWheel::new(Duration::from_secs(1), TIMER_SLOTS),
+            scheduled: Slab::new(),
+            inbound_limit: DEFAULT_INBOUND_LIMIT,
+            accept_timer: None,
+            accept_paused: None,
+            scratch: BytesBuf::new(),
+        })
+    }
+    pub fn waker(&self) -> Waker {
+        self.waker.clone()
+    }
+    //Blocks the signals for this thread and every thread it spawns afterwards,
+    //so call it before any other threads are started
+    pub fn shutdown_on_signals(&mut self, signals: &[c_int]) -> Result<(), Error> {
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for signal in signals {
+                libc::sigaddset(&mut set, *signal);
+            }
+            let err = libc::pthread_sigmask(libc::SIG_BLOCK, &set, null_mut());
+            if err != 0 {
+                return Err(Error::from_raw_os_error(err));
+            }
+            let fd = libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC);
+            if fd == -1 {
+                return Err(Error::last_os_error());
+            }
+            let fd = OwnedFd::from_raw_fd(fd);
+            if let Some(old) = self.signals.take() {
+                self.delete_connection(old.as_raw_fd())?;
+            }
+            self.ctl(
+                libc::EPOLL_CTL_ADD,
+                fd.as_raw_fd(),
+                SIGNAL_TOKEN,
+                Interest::READABLE.events(),
+            )?;
+            self.signals = Some(fd);
+        }
+        Ok(())
+    }
+    //Applies to open connections too. The wheel ticks at a quarter of the shortest timeout,
+    //so a connection is closed at most that much later than its deadline
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) -> Result<(), Error> {
+        self.timeouts = timeouts;
+        let Some(shortest) = timeouts.shortest() else {
+            if let Some(timer) = self.timer.as_ref() {
+                timer.set(Duration::ZERO, Duration::ZERO)?;
             }
+            return Ok(());
+        };
+        if self.timer.is_none() {
+            let timer = TimerFd::new()?;
+            self.ctl(
+                libc::EPOLL_CTL_ADD,
+                timer.as_raw_fd(),
+                TIMER_TOKEN,
+                Interest::READABLE.events(),
+            )?;
+            self.timer = Some(timer);
+        }
+        let tick = (shortest / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        self.wheel = TimerWheel::new(tick, TIMER_SLOTS);
+        let now = Instant::now();
+        let ids: Vec<u64> = self.connections.iter().map(|(_, conn)| conn.id).collect();
+        for id in ids {
+            self.schedule_timeout(id, now);
+        }
+        self.timer.as_ref().unwrap().set(tick, tick)
+    }
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+    //Most unread bytes a connection holds before the poller stops reading it and lets TCP push
+    //back on the sender. Applies to connections accepted afterwards
+    pub fn set_inbound_limit(&mut self, limit: usize) {
+        self.inbound_limit = limit.max(1);
+    }
+    pub fn inbound_limit(&self) -> usize {
+        self.inbound_limit
+    }
+    //Reports Event::Timer after the delay, then every interval if one is given
+    pub fn add_timer(
+        &mut self,
+        delay: Duration,
+        interval: Option<Duration>,
+    ) -> Result<TimerHandle, Error> {
+        let timer = Arc::new(TimerFd::new()?);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let index = self.scheduled.insert(Scheduled {
+            timer: Arc::clone(&timer),
+            cancelled: Arc::clone(&cancelled),
+            repeat: interval.is_some(),
         });
-
-        for _ in 0..5 {
-            poller.poll(-1, &listener, |conn| match conn.state {
-                ConnectionState::Data => {
-                    let mut buff: Vec<u8> = vec![0; 6];
-                    println!("Got Data");
-                    let stream = Arc::clone(&conn.stream);
-                    let size = stream.lock().unwrap().read(&mut buff[0..]).unwrap();
-                    buff.resize(size, 0);
-                    let str = String::from_utf8(buff).unwrap();
-                    println!("The data is {str}");
-                    data = true;
-                }
-                ConnectionState::Closed => {
-                    println!("Connection closed");
-                    closed = true;
-                }
-                ConnectionState::Opened => {
-                    println!("Connection opened");
-                    opened = true;
-                }
+        let generation = self.scheduled.generation(index).unwrap() & GENERATION_MASK;
+        let id = pack_id(index, generation);
+        let registered = self
+            .ctl(
+                libc::EPOLL_CTL_ADD,
+                timer.as_raw_fd(),
+                SCHEDULE_TAG | id,
+                Interest::READABLE.events(),
+            )
+            //A zero delay would disarm the timer instead
+            .and_then(|_| {
+                timer.set(
+                    delay.max(Duration::from_nanos(1)),
+                    interval.unwrap_or_default(),
+                )
             });
+        if let Err(err) = registered {
+            let _ = self.delete_connection(timer.as_raw_fd());
+            self.scheduled.remove(index);
+            return Err(err);
         }
-        assert!(opened, "Connection never opened");
-        assert!(data, "Conection never recieved data from socket");
-        assert!(closed, "Connection never closed");
+        Ok(TimerHandle {
+            id,
+            cancelled,
+            timer,
+        })
+    }
+    //Same as cancelling through the handle, except no event is reported
+    pub fn remove_timer(&mut self, id: u64) -> Result<bool, Error> {
+        let Some(scheduled) = self.take_scheduled(id) else {
+            return Ok(false);
+        };
+        scheduled.cancelled.store(true, Ordering::SeqCst);
+        self.delete_connection(scheduled.timer.as_raw_fd())?;
+        Ok(true)
+    }
+    //Every datagram is reported as Event::Datagram, the socket is drained with recvmmsg on each wakeup
+    pub fn add_udp(&mut self, socket: UdpSocket) -> Result<usize, Error> {
+        socket.set_nonblocking(true)?;
+        let id = self.udp_sockets.len();
+        self.ctl(
+            libc::EPOLL_CTL_ADD,
+            socket.as_raw_fd(),
+            UDP_TAG | u64::try_from(id).unwrap(),
+            Interest::READABLE.events(),
+        )?;
+        self.udp_sockets.push(Some(Arc::new(socket)));
+        self.recv_batch
+            .get_or_insert_with(|| Box::new(RecvBatch::new()));
+        Ok(id)
+    }
+    pub fn remove_udp(&mut self, id: usize) -> Result<Option<Arc<UdpSocket>>, Error> {
+        let Some(socket) = self.udp_sockets.get_mut(id).and_then(|slot| slot.take()) else {
+            return Ok(None);
+        };
+        self.delete_connection(socket.as_raw_fd())?;
+        Ok(Some(socket))
+    }
+    pub fn udp_socket(&self, id: usize) -> Option<&Arc<UdpSocket>> {
+        self.udp_sockets.get(id).and_then(|slot| slot.as_ref())
+    }
+    //Batched replies through sendmmsg, returns how many were sent before the socket filled up
+    pub fn send_datagrams(
+        &self,
+        id: usize,
+        datagrams: &[(SocketAddr, &[u8])],
+    ) -> Result<usize, Error> {
+        let socket = self.udp_socket(id).ok_or(Error::new(
+            ErrorKind::NotFound,
+            "no UDP socket with that id",
+        ))?;
+        udp::send_batch(socket, datagrams)
+    }
+    //Stops accepting on every listener, the sockets are closed
+    pub fn remove_listeners(&mut self) -> Result<(), Error> {
+        for id in 0..self.listeners.len() {
+            self.remove_listener(id)?;
+        }
+        Ok(())
+    }
+    //Shuts down every tracked connection and reports each one as closed
+    pub fn close_all<F>(&mut self, mut event_closure: F) -> Result<(), Error>
+    where
+        F: FnMut(Event),
+    {
+        let mut first_err: Option<Error> = None;
+        let indices: Vec<usize> = self.connections.iter().map(|(index, _)| index).collect();
+        for index in indices {
+            let Some(mut conn) = self.connections.remove(index) else {
+                continue;
+            };
+            conn.state = ConnectionState::Closed;
+            {
+                let stream = conn.stream.lock().unwrap();
+                if let Err(err) = self.selector.delete(stream.as_raw_fd()) {
+                    first_err.get_or_insert(err);
+                }
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            if !self.forget_closing(conn.id) {
+                event_closure(Event::Connection(conn));
+            }
+        }
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+    //Accepted connections carry the returned id in Connection::listener, TcpListener and UnixListener both work
+    pub fn add_listener<L: Into<ListenSocket>>(&mut self, listener: L) -> Result<usize, Error> {
+        let listener = listener.into();
+        listener.set_nonblocking(true)?;
+        if self.accept_timer.is_none() {
+            let timer = TimerFd::new()?;
+            self.ctl(
+                libc::EPOLL_CTL_ADD,
+                timer.as_raw_fd(),
+                ACCEPT_TOKEN,
+                Interest::READABLE.events(),
+            )?;
+            self.accept_timer = Some(timer);
+        }
+        let id = self.listeners.len();
+        let mode = ListenerMode::Level;
+        self.ctl(
+            libc::EPOLL_CTL_ADD,
+            listener.as_raw_fd(),
+            LISTENER_TAG | u64::try_from(id).unwrap(),
+            mode.events(),
+        )?;
+        self.listeners.push(Some(Listener {
+            socket: listener,
+            mode,
+        }));
+        Ok(id)
+    }
+    pub fn set_listener_mode(&mut self, id: usize, mode: ListenerMode) -> Result<(), Error> {
+        let listener = self
+            .listeners
+            .get_mut(id)
+            .and_then(|slot| slot.as_mut())
+            .ok_or(Error::new(ErrorKind::NotFound, "no listener with that id"))?;
+        listener.mode = mode;
+        //A paused listener picks up the new mode when it is re-armed
+        if self.accept_paused.is_some() {
+            return Ok(());
+        }
+        self.selector.ctl(
+            libc::EPOLL_CTL_MOD,
+            listener.socket.as_raw_fd(),
+            LISTENER_TAG | u64::try_from(id).unwrap(),
+            mode.events(),
+        )
+    }
+    pub fn remove_listener(&mut self, id: usize) -> Result<Option<ListenSocket>, Error> {
+        let Some(listener) = self.listeners.get_mut(id).and_then(|slot| slot.take()) else {
+            return Ok(None);
+        };
+        self.delete_connection(listener.socket.as_raw_fd())?;
+        Ok(Some(listener.socket))
+    }
+    //None for Unix listeners, see unix_listener
+    pub fn listener(&self, id: usize) -> Option<&TcpListener> {
+        match &self.listeners.get(id)?.as_ref()?.socket {
+            ListenSocket::Tcp(listener) => Some(listener),
+            ListenSocket::Unix(_) => None,
+        }
+    }
+    pub fn unix_listener(&self, id: usize) -> Option<&UnixListener> {
+        match &self.listeners.get(id)?.as_ref()?.socket {
+            ListenSocket::Unix(listener) => Some(listener),
+            ListenSocket::Tcp(_) => None,
+        }
+    }
+    //Handles every event from one wait, the first error found is returned after the batch is done
+    pub fn poll<F>(&mut self, timeout: i32, mut event_closure: F) -> Result<usize, PollError>
+    where
+        F: FnMut(Event),
+    {
+        let mut first_err: Option<PollError> = self.sweep_closing().err().map(PollError::from);
+        //A closed connection may have given back an fd
+        if self
+            .accept_paused
+            .is_some_and(|count| self.connections.len() < count)
+            && let Err(err) = self.resume_accepting()
+        {
+            first_err.get_or_insert(err.into());
+        }
+        self.wait(timeout)?;
+
+        //Copied out one at a time so the buffer stays in place even if a handler panics
+        for index in 0..self.events.len() {
+            let event = self.events[index];
+            let token = event.u64;
+            let handled = match token {
+                token if token & TAG_MASK == LISTENER_TAG => {
+                    self.accept(token & !TAG_MASK, &mut event_closure)
+                }
+                token if token & TAG_MASK == CONNECTION_TAG => {
+                    self.handle_connection(&event, &mut event_closure)
+                }
+                token if token < USER_TOKEN_LIMIT => {
+                    event_closure(Event::Ready(Readiness::from_event(&event)));
+                    Ok(())
+                }
+                WAKER_TOKEN => self.handle_commands(&mut event_closure),
+                SIGNAL_TOKEN => self.handle_signals(&mut event_closure),
+                TIMER_TOKEN => self.handle_timeouts(&mut event_closure),
+                ACCEPT_TOKEN => {
+                    if let Some(timer) = self.accept_timer.as_ref() {
+                        timer.drain();
+                    }
+                    self.resume_accepting().map_err(PollError::from)
+                }
+                token if token & TAG_MASK == UDP_TAG => {
+                    self.handle_udp(token & !TAG_MASK, &mut event_closure)
+                }
+                token if token & TAG_MASK == SCHEDULE_TAG => {
+                    self.handle_timer(token & !TAG_MASK, &mut event_closure)
+                }
+                token => Err(PollError::StaleEvent(token)),
+            };
+            if let Err(err) = handled {
+                first_err.get_or_insert(err);
+            }
+        }
+        let handled = self.events.len();
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(handled),
+        }
+    }
+    pub fn max_events(&self) -> u32 {
+        self.max_events
+    }
+    //Takes effect on the next poll, the buffer is only reallocated here
+    pub fn set_max_events(&mut self, max_events: u32) {
+        let max_events = max_events.max(1);
+        if max_events != self.max_events {
+            self.max_events = max_events;
+            self.events = Vec::with_capacity(usize::try_from(max_events).unwrap());
+        }
+    }
+    pub fn register<S: AsRawFd + ?Sized>(
+        &self,
+        source: &S,
+        token: u64,
+        interest: Interest,
+    ) -> Result<(), Error> {
+        Self::check_token(token)?;
+        self.ctl(
+            libc::EPOLL_CTL_ADD,
+            source.as_raw_fd(),
+            token,
+            interest.events(),
+        )
+    }
+    pub fn reregister<S: AsRawFd + ?Sized>(
+        &self,
+        source: &S,
+        token: u64,
+        interest: Interest,
+    ) -> Result<(), Error> {
+        Self::check_token(token)?;
+        self.ctl(
+            libc::EPOLL_CTL_MOD,
+            source.as_raw_fd(),
+            token,
+            interest.events(),
+        )
+    }
+    pub fn deregister<S: AsRawFd + ?Sized>(&self, source: &S) -> Result<(), Error> {
+        self.delete_connection(source.as_raw_fd())
+    }
+    pub(crate) fn check_token(token: u64) -> Result<(), Error> {
+        if token >= USER_TOKEN_LIMIT {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "token is reserved by the poller",
+            ));
+        }
+        Ok(())
+    }
+    fn handle_signals<F>(&mut self, event_closure: &mut F) -> Result<(), PollError>
+    where
+        F: FnMut(Event),
+    {
+        let Some(fd) = self.signals.as_ref() else {
+            return Err(PollError::StaleEvent(SIGNAL_TOKEN));
+        };
+        let mut received = false;
+        loop {
+            let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+            let size = unsafe {
+                libc::read(
+                    fd.as_raw_fd(),
+                    &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void,
+                    mem::size_of::<libc::signalfd_siginfo>(),
+                )
+            };
+            if size <= 0 {
+                break;
+            }
+            received = true;
+        }
+        if received {
+            self.waker.inner.triggered.store(true, Ordering::SeqCst);
+            event_closure(Event::Shutdown);
+        }
+        Ok(())
+    }
+    fn handle_commands<F>(&mut self, event_closure: &mut F) -> Result<(), PollError>
+    where
+        F: FnMut(Event),
+    {
+        let mut first_err: Option<PollError> = None;
+        for command in self.waker.take_commands() {
+            let done = match command {
+                Command::Register {
+                    source,
+                    token,
+                    interest,
+                } => self.ctl(
+                    libc::EPOLL_CTL_ADD,
+                    source.0.as_raw_fd(),
+                    token,
+                    interest.events(),
+                ),
+                Command::Deregister(fd) => self.delete_connection(fd),
+                Command::Send(id, data) => {
+                    //Only this connection is broken, its hangup is reported as Closed
+                    if let Some(conn) = self.connection_mut(id)
+                        && conn.send(&data).is_err()
+                    {
+                        let _ = conn.stream.lock().unwrap().shutdown(Shutdown::Both);
+                    }
+                    Ok(())
+                }
+                Command::Shutdown => {
+                    event_closure(Event::Shutdown);
+                    Ok(())
+                }
+            };
+            if let Err(err) = done {
+                first_err.get_or_insert(err.into());
+            }
+        }
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+    fn handle_timeouts<F>(&mut self, event_closure: &mut F) -> Result<(), PollError>
+    where
+        F: FnMut(Event),
+    {
+        let Some(timer) = self.timer.as_ref() else {
+            return Err(PollError::StaleEvent(TIMER_TOKEN));
+        };
+        timer.drain();
+        let now = Instant::now();
+        let mut due = Vec::new();
+        self.wheel.advance(now, |id| due.push(id));
+        let mut first_err: Option<PollError> = None;
+
+        for id in due {
+            let timeouts = self.timeouts;
+            let Some(conn) = self.connection_mut(id) else {
+                continue;
+            };
+            //Activity since the entry was scheduled pushes the deadline back
+            if conn
+                .deadline(&timeouts)
+                .is_none_or(|deadline| deadline > now)
+            {
+                self.schedule_timeout(id, now);
+                continue;
+            }
+            let mut conn = self.take_connection(id).expect("id was checked above");
+            conn.state = ConnectionState::TimedOut;
+            {
+                let stream = conn.stream.lock().unwrap();
+                if let Err(err) = self.delete_connection(stream.as_raw_fd()) {
+                    first_err.get_or_insert(err.into());
+                }
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            //Handlers have already seen this one close
+            if !self.forget_closing(id) {
+                event_closure(Event::Connection(conn));
+            }
+        }
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+    fn handle_udp<F>(&mut self, token: u64, event_closure: &mut F) -> Result<(), PollError>
+    where
+        F: FnMut(Event),
+    {
+        let socket_id = usize::try_from(token).unwrap();
+        let socket = self.udp_socket(socket_id).map(Arc::clone);
+        //Removed earlier in the same batch
+        let (Some(socket), Some(batch)) = (socket, self.recv_batch.as_mut()) else {
+            return Ok(());
+        };
+        batch.drain(socket.as_raw_fd(), |from, bytes| {
+            event_closure(Event::Datagram(Datagram {
+                from,
+                bytes: bytes.to_vec(),
+                socket_id,
+                socket: Arc::clone(&socket),
+            }));
+        })?;
+        Ok(())
+    }
+    fn handle_timer<F>(&mut self, id: u64, event_closure: &mut F) -> Result<(), PollError>
+    where
+        F: FnMut(Event),
+    {
+        let (index, _) = unpack_id(id);
+        let Some(scheduled) = self.scheduled_ref(id) else {
+            return Ok(());
+        };
+        let count = scheduled.timer.drain();
+        let cancelled = scheduled.cancelled.load(Ordering::SeqCst);
+        let done = cancelled || !scheduled.repeat;
+        if count == 0 && !cancelled {
+            return Ok(());
+        }
+        let mut deleted = Ok(());
+        if done {
+            let scheduled = self.scheduled.remove(index).unwrap();
+            deleted = self.delete_connection(scheduled.timer.as_raw_fd());
+        }
+        event_closure(Event::Timer(Expiry {
+            id,
+            count: if cancelled { 0 } else { count },
+            done,
+        }));
+        Ok(deleted?)
+    }
+    fn scheduled_ref(&self, id: u64) -> Option<&Scheduled> {
+        let (index, generation) = unpack_id(id);
+        if self.scheduled.generation(index)? & GENERATION_MASK != generation {
+            return None;
+        }
+        self.scheduled.get(index)
+    }
+    fn take_scheduled(&mut self, id: u64) -> Option<Scheduled> {
+        self.scheduled_ref(id)?;
+        self.scheduled.remove(unpack_id(id).0)
+    }
+    //Without a deadline the connection is checked again after the shortest timeout,
+    //in case a write stalls in the meantime
+    fn schedule_timeout(&mut self, id: u64, now: Instant) {
+        let Some(shortest) = self.timeouts.shortest() else {
+            return;
+        };
+        let timeouts = self.timeouts;
+        let Some(conn) = self.connection_mut(id) else {
+            return;
+        };
+        let deadline = conn.deadline(&timeouts).unwrap_or(now + shortest);
+        self.wheel.schedule(id, deadline);
+    }
+    fn accept<F>(&mut self, token: u64, event_closure: &mut F) -> Result<(), PollError>
+    where
+        F: FnMut(Event),
+    {
+        let listener_id = usize::try_from(token).unwrap();
+        let (fd, mode) = match self
+            .listeners
+            .get(listener_id)
+            .and_then(|slot| slot.as_ref())
+        {
+            Some(listener) => (listener.socket.as_raw_fd(), listener.mode),
+            None => return Ok(()),
+        };
+        let mut first_err: Option<PollError> = None;
+
+        loop {
+            let (stream, socket_addr) = match accept4(fd) {
+                Ok(Some(accepted)) => accepted,
+                Ok(None) => break,
+                Err(err)
+                    if matches!(
+                        err.raw_os_error(),
+                        Some(libc::EINTR) | Some(libc::ECONNABORTED) | Some(libc::EPROTO)
+                    ) =>
+                {
+                    continue;
+                }
+                Err(err)
+                    if matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE)) =>
+                {
+                    first_err.get_or_insert(err.into());
+                    if let Err(err) = self.pause_accepting() {
+                        first_err.get_or_insert(err.into());
+                    }
+                    break;
+                }
+                Err(err) => {
+                    first_err.get_or_insert(err.into());
+                    //Edge mode would never report the backlog again, re-arming makes epoll check it
+                    if mode == ListenerMode::Edge {
+                        let rearm =
+                            self.ctl(libc::EPOLL_CTL_MOD, fd, LISTENER_TAG | token, mode.events());
+                        if let Err(err) = rearm {
+                            first_err.get_or_insert(err.into());
+                        }
+                    }
+                    break;
+                }
+            };
+            if let Err(err) = self.add_stream(stream, socket_addr, listener_id, event_closure) {
+                first_err.get_or_insert(err.into());
+            }
+            if mode == ListenerMode::Level {
+                break;
+            }
+        }
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+    //Out of fds a level-triggered listener would report the same backlog on every wait, so every
+    //listener is disarmed until a connection closes or ACCEPT_BACKOFF runs out
+    fn pause_accepting(&mut self) -> Result<(), Error> {
+        if self.accept_paused.is_none() {
+            for (id, listener) in self.listeners.iter().enumerate() {
+                if let Some(listener) = listener {
+                    self.selector.ctl(
+                        libc::EPOLL_CTL_MOD,
+                        listener.socket.as_raw_fd(),
+                        LISTENER_TAG | u64::try_from(id).unwrap(),
+                        0,
+                    )?;
+                }
+            }
+        }
+        self.accept_paused = Some(self.connections.len());
+        match self.accept_timer.as_ref() {
+            Some(timer) => timer.set(ACCEPT_BACKOFF, Duration::ZERO),
+            None => Ok(()),
+        }
+    }
+    fn resume_accepting(&mut self) -> Result<(), Error> {
+        if self.accept_paused.take().is_none() {
+            return Ok(());
+        }
+        for (id, listener) in self.listeners.iter().enumerate() {
+            if let Some(listener) = listener {
+                self.selector.ctl(
+                    libc::EPOLL_CTL_MOD,
+                    listener.socket.as_raw_fd(),
+                    LISTENER_TAG | u64::try_from(id).unwrap(),
+                    listener.mode.events(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+    fn add_stream<F>(
+        &mut self,
+        stream: Stream,
+        socket_addr: Addr,
+        listener_id: usize,
+        event_closure: &mut F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(Event),
+    {
+        let now = Instant::now();
+        let mut conn = Connection {
+            id: 0,
+            listener: listener_id,
+            socket_addr,
+            peer_cred: stream.peer_cred().ok(),
+            stream: Arc::new(Mutex::new(stream)),
+            state: ConnectionState::Opened,
+            inbound: Arc::new(Mutex::new(BytesBuf::new())),
+            inbound_limit: self.inbound_limit,
+            outbound: Arc::new(Mutex::new(Outbound {
+                data: Vec::new(),
+                written: now,
+                stalled: None,
+                finish: false,
+                read_closed: false,
+                paused: false,
+            })),
+            read: now,
+            selector: Arc::clone(&self.selector),
+            release: Release {
+                outbound: Weak::new(),
+                waker: self.waker.clone(),
+            },
+        };
+        conn.release.outbound = Arc::downgrade(&conn.outbound);
+
+        let fd = conn.stream.lock().unwrap().as_raw_fd();
+        let index = self.connections.insert(conn);
+        let generation = self.connections.generation(index).unwrap() & GENERATION_MASK;
+        let id = pack_id(index, generation);
+        if let Err(err) = self.add_connection(fd, id) {
+            self.connections.remove(index);
+            return Err(err);
+        }
+        let conn = self
+            .connections
+            .get_mut(index)
+            .expect("id should be valid at this point");
+        conn.id = id;
+        event_closure(Event::Connection(conn.clone()));
+        self.schedule_timeout(id, now);
+        Ok(())
+    }
+    fn handle_connection<F>(
+        &mut self,
+        event: &epoll_event,
+        event_closure: &mut F,
+    ) -> Result<(), PollError>
+    where
+        F: FnMut(Event),
+    {
+        let id = event.u64 & !TAG_MASK;
+        //Late events for a connection that has already been dropped
+        if self.connection_mut(id).is_none() {
+            return Ok(());
+        }
+        let hangup = event.events & (EPOLLHUP | EPOLLERR) as u32 != 0;
+        let mut read_closed = event.events & EPOLLRDHUP as u32 != 0;
+        if (event.events & libc::EPOLLIN as u32) != 0 {
+            let mut scratch = mem::take(&mut self.scratch);
+            let conn = self.connection_mut(id).expect("id was checked above");
+            let room = conn
+                .inbound_limit
+                .saturating_sub(conn.inbound.lock().unwrap().len());
+            let budget = room.min(READ_BUDGET);
+            let read = scratch.read_from(&mut *conn.stream.lock().unwrap(), budget);
+            let (size, eof) = read.unwrap_or((0, true));
+            //Edge-triggered, so whatever the budget left unread needs a re-arm to be reported again.
+            //EPOLLRDHUP comes with that data and only counts once it has been read
+            let more = size == budget && !eof;
+            read_closed = eof || read_closed && !more;
+            let stored = conn.store_read(&scratch, more);
+            if size > 0 {
+                conn.state = ConnectionState::Data;
+                conn.read = Instant::now();
+                event_closure(Event::Connection(conn.clone()));
+            }
+            scratch.clear();
+            self.scratch = scratch;
+            stored?;
+        }
+        if (event.events & libc::EPOLLOUT as u32) != 0 {
+            let conn = self.connection_mut(id).expect("id was checked above");
+            //A failed flush is followed by a hangup or error event which closes the connection
+            if let Ok(true) = conn.flush() {
+                conn.state = ConnectionState::Writable;
+                event_closure(Event::Connection(conn.clone()));
+            }
+        }
+        //Nothing can be sent either, so there is no point waiting for handlers
+        if hangup {
+            let mut conn = self.take_connection(id).expect("id was checked above");
+            conn.state = ConnectionState::Closed;
+            let deleted = self.delete_connection(conn.stream.lock().unwrap().as_raw_fd());
+
+            if !self.forget_closing(id) {
+                event_closure(Event::Connection(conn));
+            }
+            deleted?;
+            return Ok(());
+        }
+        //Also called after a flush, a half-closed connection is reported once its output is out
+        self.close_read(id, read_closed, event_closure)?;
+        Ok(())
+    }
+    //Stops reading once the peer shuts down its side. Closed is reported after buffered output
+    //has gone out, the connection stays registered until sweep_closing drops it
+    fn close_read<F>(
+        &mut self,
+        id: u64,
+        peer_closed: bool,
+        event_closure: &mut F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(Event),
+    {
+        if self.closing.contains(&id) {
+            return Ok(());
+        }
+        let Some(conn) = self.connection_mut(id) else {
+            return Ok(());
+        };
+        {
+            let mut outbound = conn.outbound.lock().unwrap();
+            if peer_closed && !outbound.read_closed {
+                outbound.read_closed = true;
+                conn.arm(&outbound, conn.stream.lock().unwrap().as_raw_fd())?;
+            }
+            if !outbound.read_closed || !outbound.data.is_empty() {
+                return Ok(());
+            }
+        }
+        conn.state = ConnectionState::Closed;
+        let conn = conn.clone();
+        self.closing.insert(id);
+        event_closure(Event::Connection(conn));
+        Ok(())
+    }
+    //Frees half-closed connections once their output is out and no handler holds a copy,
+    //Release wakes the poller when the last one is dropped
+    fn sweep_closing(&mut self) -> Result<(), Error> {
+        let mut first_err: Option<Error> = None;
+        //Taken out and put back so the set keeps its allocation
+        let mut closing = mem::take(&mut self.closing);
+        closing.retain(|&id| {
+            let Some(conn) = self.connection_mut(id) else {
+                return false;
+            };
+            if conn.pending() > 0 || Arc::strong_count(&conn.outbound) > 1 {
+                return true;
+            }
+            let conn = self.take_connection(id).expect("id was checked above");
+            if let Err(err) = self.delete_connection(conn.stream.lock().unwrap().as_raw_fd()) {
+                first_err.get_or_insert(err);
+            }
+            false
+        });
+        self.closing = closing;
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+    //Returns whether the connection had already been reported as Closed
+    fn forget_closing(&mut self, id: u64) -> bool {
+        self.closing.remove(&id)
+    }
+    //Events for an id whose generation no longer matches its slot belong to a closed connection
+    fn connection_mut(&mut self, id: u64) -> Option<&mut Connection> {
+        let (index, generation) = unpack_id(id);
+        if self.connections.generation(index)? & GENERATION_MASK != generation {
+            return None;
+        }
+        self.connections.get_mut(index)
+    }
+    fn take_connection(&mut self, id: u64) -> Option<Connection> {
+        self.connection_mut(id)?;
+        self.connections.remove(unpack_id(id).0)
+    }
+    fn wait(&mut self, timeout: i32) -> Result<(), Error> {
+        self.events.clear();
+        unsafe {
+            //Blocks process. The kernel is only ever told about memory the buffer owns
+            let size = libc::epoll_wait(
+                i32::try_from(self.selector.epollfd).unwrap(),
+                self.events.as_mut_ptr(),
+                i32::try_from(self.events.capacity()).unwrap_or(i32::MAX),
+                timeout,
+            );
+            if size == -1 {
+                return Err(Error::last_os_error());
+            }
+            //epoll_wait wrote this many events and never more than the capacity
+            self.events.set_len(usize::try_from(size).unwrap());
+        }
+        Ok(())
+    }
+    fn add_connection(&self, fd: c_int, id: u64) -> Result<(), Error> {
+        let interest = Interest::READABLE | Interest::EDGE;
+        self.ctl(
+            libc::EPOLL_CTL_ADD,
+            fd,
+            CONNECTION_TAG | id,
+            interest.events(),
+        )
+    }
+    fn delete_connection(&self, fd: c_int) -> Result<(), Error> {
+        self.selector.delete(fd)
+    }
+    fn ctl(&self, op: c_int, fd: c_int, token: u64, events: u32) -> Result<(), Error> {
+        self.selector.ctl(op, fd, token, events)
+    }
+}
+
+//Make request to poller
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::thread;
+
+    //A poller with one listener on a free port
+    fn listening_poller() -> (Poller, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut poller = Poller::new(20).expect("Did not create poller");
+        poller.add_listener(listener).expect("Did not add listener");
+        (poller, addr)
+    }
+    //Polls until on_event returns true or the limit runs out, returns whether it did
+    fn poll_until<F>(poller: &mut Poller, limit: Duration, mut on_event: F) -> bool
+    where
+        F: FnMut(Event) -> bool,
+    {
+        let deadline = Instant::now() + limit;
+        let mut done = false;
+        while !done && Instant::now() < deadline {
+            match poller.poll(100, |event| done |= on_event(event)) {
+                Ok(_) | Err(PollError::Interrupted) => {}
+                Err(err) => panic!("Poll failed {err}"),
+            }
+        }
+        done
+    }
+
+    #[test]
+    fn poller_test() {
+        let (mut poller, addr) = listening_poller();
+
+        let (mut closed, mut opened, mut data) = (false, false, false);
+
+        thread::spawn(move || {
+            for _ in 0..5 {
+                let mut stream =
+                    TcpStream::connect(addr).expect("Could not connect to test server");
+                stream
+                    .write_all("Blah".as_bytes())
+                    .expect("Error sending response");
+            }
+        });
+
+        poll_until(&mut poller, Duration::from_secs(5), |event| {
+            if let Event::Connection(conn) = event {
+                match conn.state {
+                    ConnectionState::Data => {
+                        println!("Got Data");
+                        let buff = conn.inbound().take();
+                        let str = String::from_utf8(buff).unwrap();
+                        println!("The data is {str}");
+                        data = true;
+                    }
+                    ConnectionState::Closed => {
+                        println!("Connection closed");
+                        closed = true;
+                    }
+                    ConnectionState::Opened => {
+                        println!("Connection opened");
+                        opened = true;
+                    }
+                    ConnectionState::Writable | ConnectionState::TimedOut => {}
+                }
+            }
+            opened && data && closed
+        });
+        assert!(opened, "Connection never opened");
+        assert!(data, "Conection never recieved data from socket");
+        assert!(closed, "Connection never closed");
+    }
+
+    #[test]
+    fn source_test() {
+        let mut poller = Poller::new(20).expect("Did not create poller");
+        let (mut writer, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+        poller
+            .register(&reader, 42, Interest::READABLE)
+            .expect("Could not register source");
+        poller
+            .register(&reader, USER_TOKEN_LIMIT, Interest::READABLE)
+            .expect_err("Registered a reserved token");
+
+        writer.write_all("Blah".as_bytes()).unwrap();
+
+        let mut ready = None;
+        poller
+            .poll(1000, |event| {
+                if let Event::Ready(readiness) = event {
+                    ready = Some(readiness);
+                }
+            })
+            .expect("Poll failed");
+        let ready = ready.expect("Source was never ready");
+        assert_eq!(ready.token, 42);
+        assert!(ready.readable);
+
+        poller.deregister(&reader).unwrap();
+    }
+
+    #[test]
+    fn multi_listener_test() {
+        let mut poller = Poller::new(20).expect("Did not create poller");
+        let mut addrs = Vec::new();
+        for _ in 0..3 {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            poller.add_listener(listener).expect("Did not add listener");
+        }
+        let _streams: Vec<TcpStream> = addrs
+            .iter()
+            .map(|addr| TcpStream::connect(addr).expect("Could not connect to test server"))
+            .collect();
+
+        let mut seen: Vec<usize> = Vec::new();
+        poll_until(&mut poller, Duration::from_secs(5), |event| {
+            if let Event::Connection(conn) = event
+                && let ConnectionState::Opened = conn.state
+            {
+                seen.push(conn.listener);
+            }
+            seen.len() == addrs.len()
+        });
+        seen.sort();
+        assert_eq!(seen, vec![0, 1, 2]);
+
+        let removed = poller.remove_listener(1).unwrap();
+        assert!(removed.is_some());
+        assert!(poller.listener(1).is_none());
+    }
+
+    #[test]
+    fn stale_event_test() {
+        let (mut poller, addr) = listening_poller();
+
+        let open_one = |poller: &mut Poller| {
+            let stream = TcpStream::connect(addr).expect("Could not connect to test server");
+            let mut id = None;
+            poll_until(poller, Duration::from_secs(5), |event| {
+                if let Event::Connection(conn) = event
+                    && let ConnectionState::Opened = conn.state
+                {
+                    id = Some(conn.id);
+                }
+                id.is_some()
+            });
+            (stream, id.expect("Connection was not opened"))
+        };
+
+        let (first, old_id) = open_one(&mut poller);
+        drop(first);
+        let closed = poll_until(
+            &mut poller,
+            Duration::from_secs(5),
+            |event| matches!(event, Event::Connection(conn) if matches!(conn.state, ConnectionState::Closed)),
+        );
+        assert!(closed, "First connection was not closed");
+
+        //The second connection takes the same slot with a new generation
+        let (_second, new_id) = open_one(&mut poller);
+        println!("Old id {old_id:#x} new id {new_id:#x}");
+        assert_ne!(old_id, new_id);
+        assert_eq!(unpack_id(old_id).0, unpack_id(new_id).0);
+
+        let late = epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: CONNECTION_TAG | old_id,
+        };
+        let mut delivered = false;
+        let handled = poller.handle_connection(&late, &mut |_| delivered = true);
+        assert!(handled.is_ok(), "Late event was reported as an error");
+        assert!(!delivered, "Late event reached the new connection");
+
+        //Sends queued for the closed connection are dropped the same way
+        poller.waker().send(old_id, b"late".to_vec()).unwrap();
+        poller
+            .poll(100, |_| {})
+            .expect("Late send was reported as an error");
+
+        //A tag the poller never hands out is still an error
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        let unknown = 0x8F << 56;
+        poller
+            .ctl(
+                libc::EPOLL_CTL_ADD,
+                reader.as_raw_fd(),
+                unknown,
+                Interest::READABLE.events(),
+            )
+            .unwrap();
+        writer.write_all(b"x").unwrap();
+        let polled = poller.poll(1000, |_| {});
+        assert!(matches!(polled, Err(PollError::StaleEvent(token)) if token == unknown));
+    }
+
+    #[test]
+    fn max_events_test() {
+        let (mut poller, addr) = listening_poller();
+        poller.set_max_events(1);
+        assert_eq!(poller.max_events(), 1);
+
+        let _streams: Vec<TcpStream> = (0..3)
+            .map(|_| TcpStream::connect(addr).expect("Could not connect to test server"))
+            .collect();
+        let buffer = poller.events.as_ptr();
+        let mut opened = 0;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while opened < 3 && Instant::now() < deadline {
+            let handled = poller
+                .poll(100, |event| {
+                    if let Event::Connection(conn) = event
+                        && let ConnectionState::Opened = conn.state
+                    {
+                        opened += 1;
+                    }
+                })
+                .expect("Poll failed");
+            assert!(handled <= 1, "Wait returned {handled} events");
+        }
+        assert_eq!(opened, 3);
+        assert_eq!(
+            poller.events.as_ptr(),
+            buffer,
+            "Event buffer was reallocated"
+        );
+    }
+
+    #[test]
+    fn poll_panic_test() {
+        let mut poller = Poller::new(20).expect("Did not create poller");
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        poller
+            .register(&reader, 1, Interest::READABLE)
+            .expect("Could not register source");
+        writer.write_all(b"x").unwrap();
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            poller.poll(1000, |_| panic!("handler failed"))
+        }));
+        assert!(panicked.is_err());
+        //The buffer is still there, so the next wait works
+        let mut ready = false;
+        poller
+            .poll(1000, |event| ready = matches!(event, Event::Ready(_)))
+            .expect("Poller did not survive the panic");
+        assert!(ready);
+    }
+
+    #[test]
+    fn backpressure_test() {
+        let (mut poller, addr) = listening_poller();
+
+        let payload: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = payload.clone();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).expect("Could not connect to test server");
+            let mut received = vec![0; expected.len()];
+            stream.read_exact(&mut received).unwrap();
+            assert!(received == expected, "Payload was corrupted");
+        });
+
+        let drained = poll_until(&mut poller, Duration::from_secs(10), |event| {
+            let Event::Connection(conn) = event else {
+                return false;
+            };
+            match conn.state {
+                ConnectionState::Opened => {
+                    conn.send(&payload).expect("Could not queue payload");
+                    assert!(conn.pending() > 0, "Socket took the whole payload");
+                    false
+                }
+                ConnectionState::Writable => {
+                    assert_eq!(conn.pending(), 0);
+                    true
+                }
+                _ => false,
+            }
+        });
+        assert!(drained, "Outbound buffer never drained");
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn edge_listener_test() {
+        let (mut poller, addr) = listening_poller();
+        poller.set_max_events(64);
+        poller.set_listener_mode(0, ListenerMode::Edge).unwrap();
+
+        let _streams: Vec<TcpStream> = (0..20)
+            .map(|_| TcpStream::connect(addr).expect("Could not connect to test server"))
+            .collect();
+
+        let mut opened = 0;
+        poller
+            .poll(1000, |event| {
+                if let Event::Connection(conn) = event
+                    && let ConnectionState::Opened = conn.state
+                {
+                    opened += 1;
+                }
+            })
+            .expect("Poll failed");
+        assert_eq!(opened, 20, "Burst was not accepted in one wakeup");
+    }
+
+    #[test]
+    fn timeout_test() {
+        let (mut poller, addr) = listening_poller();
+        poller
+            .set_timeouts(Timeouts {
+                read: Some(Duration::from_millis(200)),
+                ..Timeouts::default()
+            })
+            .unwrap();
+
+        let mut silent = TcpStream::connect(addr).expect("Could not connect to test server");
+        let silent_addr = silent.local_addr().unwrap();
+        let chatty = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).expect("Could not connect to test server");
+            for _ in 0..12 {
+                stream.write_all(b"ping").unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+            //Kept open so it times out instead of closing
+            stream
+        });
+
+        let start = Instant::now();
+        let mut timed_out = Vec::new();
+        poll_until(&mut poller, Duration::from_secs(5), |event| {
+            if let Event::Connection(conn) = event
+                && let ConnectionState::TimedOut = conn.state
+            {
+                println!("{} timed out after {:?}", conn.socket_addr, start.elapsed());
+                timed_out.push((conn.socket_addr.inet().unwrap(), start.elapsed()));
+            }
+            timed_out.len() == 2
+        });
+        chatty.join().unwrap();
+        assert_eq!(timed_out.len(), 2, "Connections did not time out");
+        assert_eq!(
+            timed_out[0].0, silent_addr,
+            "Busy connection timed out first"
+        );
+        assert!(timed_out[1].1 >= Duration::from_millis(600));
+        assert!(poller.connections.is_empty(), "Slots were not reclaimed");
+
+        let mut buff = [0; 4];
+        assert_eq!(
+            silent.read(&mut buff).unwrap(),
+            0,
+            "Socket was not shut down"
+        );
+    }
+
+    #[test]
+    fn waker_test() {
+        let (mut poller, addr) = listening_poller();
+        let waker = poller.waker();
+
+        //A wake with nothing queued still ends a wait with no timeout
+        let woken = waker.clone();
+        let waking = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            woken.wake().unwrap();
+        });
+        let handled = poller.poll(-1, |_| {}).expect("Poll failed");
+        assert_eq!(handled, 1);
+        waking.join().unwrap();
+
+        let mut client = TcpStream::connect(addr).expect("Could not connect to test server");
+        let mut id = None;
+        poll_until(&mut poller, Duration::from_secs(5), |event| {
+            if let Event::Connection(conn) = event {
+                id = Some(conn.id);
+            }
+            id.is_some()
+        });
+        let (mut writer, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+        let remote = waker.clone();
+        thread::spawn(move || {
+            let reader = Arc::new(reader);
+            remote.send(id.unwrap(), b"pong".to_vec()).unwrap();
+            remote
+                .register(Arc::clone(&reader), 7, Interest::READABLE)
+                .unwrap();
+            writer.write_all(b"x").unwrap();
+            //Keeps the source open until the poller has registered it
+            thread::sleep(Duration::from_millis(200));
+            drop(reader);
+        });
+
+        let ready = poll_until(
+            &mut poller,
+            Duration::from_secs(5),
+            |event| matches!(event, Event::Ready(readiness) if readiness.token == 7),
+        );
+        assert!(ready, "Source registered through the waker was never ready");
+        let mut buff = [0; 4];
+        client.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff, b"pong");
+
+        waker.shutdown().unwrap();
+        let mut stopped = false;
+        poller
+            .poll(1000, |event| stopped = matches!(event, Event::Shutdown))
+            .expect("Poll failed");
+        assert!(stopped && waker.is_triggered());
+    }
+
+    #[test]
+    fn framed_read_test() {
+        let (mut poller, addr) = listening_poller();
+
+        //More than one read's worth, split across writes that break lines apart
+        let lines: Vec<String> = (0..2000).map(|i| format!("line {i}")).collect();
+        let payload = lines.join("\n") + "\n";
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).expect("Could not connect to test server");
+            for chunk in payload.as_bytes().chunks(777) {
+                stream.write_all(chunk).unwrap();
+            }
+        });
+
+        let framer = crate::framer::Lines::default();
+        let mut received: Vec<String> = Vec::new();
+        let closed = poll_until(&mut poller, Duration::from_secs(5), |event| {
+            let Event::Connection(conn) = event else {
+                return false;
+            };
+            while let Some(frame) = conn.next_frame(&framer).unwrap() {
+                received.push(String::from_utf8(frame).unwrap());
+            }
+            matches!(conn.state, ConnectionState::Closed)
+        });
+        client.join().unwrap();
+        println!("Received {} lines", received.len());
+        assert!(closed, "EOF did not close the connection");
+        assert!(received == lines, "Lines were lost or reordered");
+    }
+
+    #[test]
+    fn udp_test() {
+        let mut poller = Poller::new(20).expect("Did not create poller");
+        let id = poller
+            .add_udp(UdpSocket::bind("127.0.0.1:0").unwrap())
+            .expect("Did not add UDP socket");
+        let server_addr = poller.udp_socket(id).unwrap().local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        for i in 0..50u32 {
+            client.send_to(&i.to_be_bytes(), server_addr).unwrap();
+        }
+
+        let mut received: Vec<(SocketAddr, Vec<u8>)> = Vec::new();
+        poll_until(&mut poller, Duration::from_secs(5), |event| {
+            if let Event::Datagram(datagram) = event {
+                assert_eq!(datagram.socket_id, id);
+                received.push((datagram.from, datagram.bytes));
+            }
+            received.len() == 50
+        });
+        assert_eq!(received.len(), 50, "Datagrams were lost");
+
+        let replies: Vec<(SocketAddr, &[u8])> = received
+            .iter()
+            .map(|(from, bytes)| (*from, bytes.as_slice()))
+            .collect();
+        let sent = poller.send_datagrams(id, &replies).unwrap();
+        println!("Replied to {sent} datagrams");
+        assert_eq!(sent, 50);
+        for i in 0..50u32 {
+            let mut buff = [0; 4];
+            let (size, from) = client.recv_from(&mut buff).unwrap();
+            assert_eq!((size, from), (4, server_addr));
+            assert_eq!(u32::from_be_bytes(buff), i);
+        }
+        assert!(poller.remove_udp(id).unwrap().is_some());
+    }
+
+    #[test]
+    fn unix_listener_test() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr as UnixSocketAddr;
+
+        let name = format!("rust_epoll_test_{}", std::process::id());
+        let addr = UnixSocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let mut poller = Poller::new(20).expect("Did not create poller");
+        let id = poller
+            .add_listener(UnixListener::bind_addr(&addr).unwrap())
+            .expect("Did not add listener");
+        assert!(poller.listener(id).is_none());
+        assert!(poller.unix_listener(id).is_some());
+
+        let mut client = UnixStream::connect_addr(&addr).expect("Could not connect to test server");
+        client.write_all(b"local").unwrap();
+        drop(client);
+
+        let mut states = Vec::new();
+        let mut received = Vec::new();
+        poll_until(&mut poller, Duration::from_secs(5), |event| {
+            let Event::Connection(conn) = event else {
+                return false;
+            };
+            if let ConnectionState::Opened = conn.state {
+                println!("Opened from {} by {:?}", conn.socket_addr, conn.peer_cred);
+                let cred = conn.peer_cred.expect("No peer credentials");
+                assert_eq!(cred.pid, i32::try_from(std::process::id()).unwrap());
+                assert!(matches!(conn.socket_addr, Addr::Unix(_)));
+            }
+            received.extend(conn.inbound().take());
+            let closed = matches!(conn.state, ConnectionState::Closed);
+            states.push(conn.state);
+            closed
+        });
+        println!("States {states:?}");
+        assert!(matches!(states.first(), Some(ConnectionState::Opened)));
+        assert!(matches!(states.last(), Some(ConnectionState::Closed)));
+        assert_eq!(received, b"local");
+    }
+
+    #[test]
+    fn inbound_limit_test() {
+        const LIMIT: usize = 100 * 1024;
+        const TOTAL: usize = 16 * 1024 * 1024;
+        let (mut poller, addr) = listening_poller();
+        poller.set_inbound_limit(LIMIT);
+
+        let sender = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).expect("Could not connect to test server");
+            let payload: Vec<u8> = (0..TOTAL).map(|i| (i % 251) as u8).collect();
+            stream.write_all(&payload).expect("Error sending payload");
+        });
+
+        //Nothing is consumed, so reading stops at the limit
+        let mut held = None;
+        poll_until(&mut poller, Duration::from_secs(5), |event| {
+            let Event::Connection(conn) = event else {
+                return false;
+            };
+            let len = conn.inbound().len();
+            assert!(len <= LIMIT, "Read past the limit: {len}");
+            if len == LIMIT {
+                held = Some(conn);
+            }
+            held.is_some()
+        });
+        let conn = held.expect("Inbound never filled");
+        poll_until(&mut poller, Duration::from_millis(300), |event| {
+            if let Event::Connection(conn) = event {
+                assert!(
+                    !matches!(conn.state, ConnectionState::Data),
+                    "Read while paused"
+                );
+            }
+            false
+        });
+        assert!(!sender.is_finished(), "Sender was not pushed back");
+
+        //Taking the data resumes reading, each wakeup reads at most the budget
+        let mut received = conn.inbound().take();
+        poll_until(&mut poller, Duration::from_secs(10), |event| {
+            if let Event::Connection(conn) = event {
+                let mut inbound = conn.inbound();
+                assert!(inbound.len() <= READ_BUDGET, "Read past the budget");
+                received.extend(inbound.take());
+            }
+            received.len() == TOTAL
+        });
+        println!("Received {} bytes", received.len());
+        assert_eq!(received.len(), TOTAL);
+        assert!(
+            received
+                .iter()
+                .enumerate()
+                .all(|(i, byte)| *byte == (i % 251) as u8)
+        );
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn reset_send_test() {
+        let (mut poller, addr) = listening_poller();
+        let client = TcpStream::connect(addr).expect("Could not connect to test server");
+        let mut id = None;
+        poll_until(&mut poller, Duration::from_secs(5), |event| {
+            if let Event::Connection(conn) = event {
+                id = Some(conn.id);
+            }
+            id.is_some()
+        });
+
+        //Queued before the peer resets, so the send runs into the reset
+        poller
+            .waker()
+            .send(id.expect("Connection was not opened"), b"hello".to_vec())
+            .unwrap();
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        let err = unsafe {
+            libc::setsockopt(
+                client.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                &linger as *const libc::linger as *const libc::c_void,
+                libc::socklen_t::try_from(mem::size_of::<libc::linger>()).unwrap(),
+            )
+        };
+        assert_eq!(err, 0);
+        drop(client);
+        thread::sleep(Duration::from_millis(50));
+
+        //poll_until fails the test if the reset comes back as an error
+        let closed = poll_until(
+            &mut poller,
+            Duration::from_secs(5),
+            |event| matches!(event, Event::Connection(conn) if matches!(conn.state, ConnectionState::Closed)),
+        );
+        assert!(closed, "Reset connection was not closed");
+    }
+
+    #[test]
+    fn fd_exhaustion_test() {
+        //The fd limit is per process, so the test reruns itself in a child to spare the other tests
+        if std::env::var_os("RUST_EPOLL_FD_LIMIT_CHILD").is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "polller::test::fd_exhaustion_test",
+                    "--exact",
+                    "--nocapture",
+                ])
+                .env("RUST_EPOLL_FD_LIMIT_CHILD", "1")
+                .status()
+                .expect("Could not run the child test");
+            assert!(status.success(), "Child test failed");
+            return;
+        }
+        let (mut poller, addr) = listening_poller();
+        //Waits in the backlog until there is an fd to accept it with
+        let _client = TcpStream::connect(addr).expect("Could not connect to test server");
+        unsafe {
+            let mut limit: libc::rlimit = mem::zeroed();
+            assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit), 0);
+            limit.rlim_cur = limit.rlim_cur.min(128);
+            assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &limit), 0);
+        }
+        let mut filler = Vec::new();
+        while let Ok(file) = std::fs::File::open("/dev/null") {
+            filler.push(file);
+        }
+
+        match poller.poll(1000, |_| {}) {
+            Err(PollError::TooManyFiles(_)) => {}
+            other => panic!("Expected TooManyFiles, got {other:?}"),
+        }
+        //Disarmed, otherwise the listener reports the same backlog again right away
+        match poller.poll(50, |_| {}) {
+            Ok(0) => {}
+            other => panic!("Listener was not disarmed, got {other:?}"),
+        }
+
+        //The backoff re-arms the listener once there are fds again
+        drop(filler);
+        let opened = poll_until(
+            &mut poller,
+            Duration::from_secs(5),
+            |event| matches!(event, Event::Connection(conn) if matches!(conn.state, ConnectionState::Opened)),
+        );
+        assert!(opened, "Connection was not accepted after fds came back");
     }
 }
@@ -1,209 +1,586 @@
 use std::{
-    array,
-    collections::VecDeque,
     error::Error,
-    fmt::Debug,
-    sync::{Arc, Condvar, Mutex},
-    thread::JoinHandle,
+    fmt::{self, Debug, Display},
+    io, mem,
+    ops::Deref,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle, Thread},
 };
 
+use crate::deque::{Injector, Steal, Stealer, Worker};
+
+pub type ThreadErr = Box<dyn 'static + Error + Send>;
+pub type ThreadFunc = Box<dyn FnOnce(usize) -> Result<(), ThreadErr> + Send>;
+pub type PanicFunc = Arc<dyn Fn(usize, ThreadErr) + Send + Sync>;
+
+//What a task that panicked is reported as
 #[derive(Debug)]
-pub enum RingBufferError {
-    BuffferFull,
-    BuffferEmpty,
+pub struct TaskPanic {
+    pub worker: usize,
+    pub message: String,
+}
+impl TaskPanic {
+    fn new(worker: usize, payload: &(dyn std::any::Any + Send)) -> Self {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic payload".to_string()
+        };
+        Self { worker, message }
+    }
+}
+impl Display for TaskPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "task panicked on worker {}: {}",
+            self.worker, self.message
+        )
+    }
+}
+impl Error for TaskPanic {}
+
+//A panicking task never holds a pool lock, so a poisoned one is still consistent
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
 }
 
-#[derive(Debug, Clone)]
-struct RingBuffer<T, const S: usize> {
-    head: Option<usize>,
-    tail: Option<usize>,
-    data: [Option<T>; S],
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    //Workers finish everything in the global and local queues before exiting
+    Drain,
+    //Queued tasks are dropped, workers exit after the task they are running
+    Abort,
 }
 
-impl<T: Clone, const S: usize> Default for RingBuffer<T, S> {
+//How long a worker keeps looking for work before it parks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpinPolicy {
+    //Empty polls with a spin hint in between
+    pub spins: u32,
+    //Empty polls after the spins that give up the core in between
+    pub yields: u32,
+}
+impl Default for SpinPolicy {
     fn default() -> Self {
         Self {
-            head: None,
-            tail: None,
-            data: array::from_fn(|_| None),
+            spins: 32,
+            yields: 8,
         }
     }
 }
-impl<T: Clone, const S: usize> RingBuffer<T, S> {
-    pub fn enqueue(&mut self, payload: T) -> Result<(), RingBufferError> {
-        if self.is_full() {
-            return Err(RingBufferError::BuffferFull);
+impl SpinPolicy {
+    //Parks as soon as a worker finds nothing to do
+    pub fn park_immediately() -> Self {
+        Self {
+            spins: 0,
+            yields: 0,
         }
-        let next = if self.tail.is_some() {
-            (self.tail.unwrap() + 1) % self.data.len()
+    }
+    //Returns false once the worker should park
+    fn backoff(&self, idle: u32) -> bool {
+        if idle <= self.spins {
+            std::hint::spin_loop();
+        } else if idle <= self.spins + self.yields {
+            thread::yield_now();
         } else {
-            0
-        };
-        self.data[next] = Some(payload);
-        self.tail = Some(next);
-        if self.is_empty() {
-            self.head = self.tail;
+            return false;
         }
-        Ok(())
+        true
     }
-    pub fn dequeue(&mut self) -> Result<T, RingBufferError> {
-        if self.is_empty() {
-            return Err(RingBufferError::BuffferEmpty);
+}
+
+const RUNNING: u8 = 0;
+const PARKED: u8 = 1;
+const NOTIFIED: u8 = 2;
+
+struct Parker {
+    state: AtomicU8,
+    //Replaced when a dead worker is respawned
+    thread: Mutex<Option<Thread>>,
+}
+impl Parker {
+    fn unpark(&self) {
+        if let Some(thread) = lock(&self.thread).as_ref() {
+            thread.unpark();
         }
-        let temp = self.data[self.head.unwrap()].take().to_owned().unwrap();
+    }
+}
 
-        if self.head == self.tail {
-            self.head = None;
-            self.tail = None;
-        } else {
-            self.head = Some((self.head.unwrap() + 1) % self.data.len());
+struct Shared {
+    injector: Injector<ThreadFunc>,
+    stealers: Vec<Stealer<ThreadFunc>>,
+    parkers: Vec<Parker>,
+    parked: AtomicUsize,
+    spin: SpinPolicy,
+    on_panic: Option<PanicFunc>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    pending: AtomicUsize,
+    draining: AtomicBool,
+    aborting: AtomicBool,
+}
+impl Shared {
+    fn is_idle(&self) -> bool {
+        self.injector.is_empty() && self.stealers.iter().all(|stealer| stealer.is_empty())
+    }
+    fn stopping(&self) -> bool {
+        self.draining.load(Ordering::SeqCst) || self.aborting.load(Ordering::SeqCst)
+    }
+    fn park(&self, id: usize) {
+        let parker = &self.parkers[id];
+        parker.state.store(PARKED, Ordering::SeqCst);
+        self.parked.fetch_add(1, Ordering::SeqCst);
+        //Work queued before the state was published would not unpark anyone
+        if (!self.is_idle() || self.stopping())
+            && parker
+                .state
+                .compare_exchange(PARKED, RUNNING, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        {
+            self.parked.fetch_sub(1, Ordering::SeqCst);
+        }
+        while parker.state.load(Ordering::SeqCst) == PARKED {
+            thread::park();
+        }
+        parker.state.store(RUNNING, Ordering::SeqCst);
+    }
+    //Wakes a single parked worker, if there is one
+    fn unpark_one(&self) {
+        if self.parked.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        for parker in self.parkers.iter() {
+            if parker
+                .state
+                .compare_exchange(PARKED, NOTIFIED, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                self.parked.fetch_sub(1, Ordering::SeqCst);
+                parker.unpark();
+                return;
+            }
+        }
+    }
+    fn unpark_all(&self) {
+        for parker in self.parkers.iter() {
+            if parker
+                .state
+                .compare_exchange(PARKED, NOTIFIED, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                self.parked.fetch_sub(1, Ordering::SeqCst);
+            }
+            parker.unpark();
+        }
+    }
+    //Own queue first, then a batch from the injector, then the other workers' queues
+    fn find_task(&self, id: usize, local: &Worker<ThreadFunc>) -> Option<ThreadFunc> {
+        if let Some(task) = local.pop() {
+            return Some(task);
+        }
+        if let Some(task) = self.injector.pop() {
+            //Moving a batch over gives idle workers something to steal
+            while local.len() < local.capacity() / 2
+                && let Some(next) = self.injector.pop()
+            {
+                if let Err(next) = local.push(next) {
+                    self.injector.push(next);
+                    break;
+                }
+            }
+            return Some(task);
         }
+        let workers = self.stealers.len();
+        (1..workers).find_map(|offset| steal(&self.stealers[(id + offset) % workers]))
+    }
+}
 
-        Ok(temp)
+#[derive(Clone)]
+pub struct ThreadPoolBuilder {
+    workers: usize,
+    queue_capacity: usize,
+    spin: SpinPolicy,
+    on_panic: Option<PanicFunc>,
+}
+impl Debug for ThreadPoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadPoolBuilder")
+            .field("workers", &self.workers)
+            .field("queue_capacity", &self.queue_capacity)
+            .field("spin", &self.spin)
+            .field("on_panic", &self.on_panic.is_some())
+            .finish()
+    }
+}
+impl Default for ThreadPoolBuilder {
+    fn default() -> Self {
+        Self {
+            workers: thread::available_parallelism().map_or(1, |n| n.get()),
+            queue_capacity: 64,
+            spin: SpinPolicy::default(),
+            on_panic: None,
+        }
     }
-    pub fn steal(&mut self) -> Result<T, RingBufferError> {
-        if self.is_empty() {
-            return Err(RingBufferError::BuffferEmpty);
+}
+impl ThreadPoolBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+    //Size of each worker's local queue, rounded up to a power of two. The injector is unbounded
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity.max(1);
+        self
+    }
+    pub fn spin_policy(mut self, spin: SpinPolicy) -> Self {
+        self.spin = spin;
+        self
+    }
+    //Called with the worker id and a TaskPanic whenever a task panics, instead of printing it
+    pub fn on_panic<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(usize, ThreadErr) + 'static + Send + Sync,
+    {
+        self.on_panic = Some(Arc::new(callback));
+        self
+    }
+    pub fn build(self) -> ThreadPool {
+        let locals: Vec<Worker<ThreadFunc>> = (0..self.workers)
+            .map(|_| Worker::new(self.queue_capacity))
+            .collect();
+        ThreadPool {
+            shared: Arc::new(Shared {
+                injector: Injector::new(),
+                stealers: locals.iter().map(Worker::stealer).collect(),
+                parkers: (0..self.workers)
+                    .map(|_| Parker {
+                        state: AtomicU8::new(RUNNING),
+                        thread: Mutex::new(None),
+                    })
+                    .collect(),
+                parked: AtomicUsize::new(0),
+                spin: self.spin,
+                on_panic: self.on_panic,
+                threads: Mutex::new(Vec::new()),
+                pending: AtomicUsize::new(0),
+                draining: AtomicBool::new(false),
+                aborting: AtomicBool::new(false),
+            }),
+            locals: Mutex::new(locals),
         }
+    }
+}
 
-        let temp = self.data[self.tail.unwrap()].take().to_owned().unwrap();
-        if self.head == self.tail {
-            self.head = None;
-            self.tail = None;
-        } else {
-            self.tail = Some((self.tail.unwrap() + (self.data.len() - 1)) % self.data.len());
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    //Owner ends of the local queues, moved into the workers by dispatch
+    locals: Mutex<Vec<Worker<ThreadFunc>>>,
+}
+impl Default for ThreadPool {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+impl ThreadPool {
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+    pub fn workers(&self) -> usize {
+        self.shared.stealers.len()
+    }
+
+    pub fn dispatch(&self) {
+        let locals = mem::take(&mut *lock(&self.locals));
+        for (id, local) in locals.into_iter().enumerate() {
+            spawn_worker(&self.shared, id, local).expect("Could not spawn worker thread");
         }
+    }
 
-        Ok(temp)
+    //Joins every worker, only returns once the pool has been shut down
+    pub fn wait(&self) {
+        //Workers that die while being joined push their replacement first
+        loop {
+            let threads = mem::take(&mut *lock(&self.shared.threads));
+            if threads.is_empty() {
+                break;
+            }
+            for thread in threads {
+                let _ = thread.join();
+            }
+        }
+    }
+    pub fn shutdown(&self, mode: ShutdownMode) {
+        match mode {
+            ShutdownMode::Drain => {
+                self.shared.draining.store(true, Ordering::SeqCst);
+            }
+            ShutdownMode::Abort => {
+                self.shared.aborting.store(true, Ordering::SeqCst);
+                let mut dropped = 0;
+                while self.shared.injector.pop().is_some() {
+                    dropped += 1;
+                }
+                for stealer in self.shared.stealers.iter() {
+                    while steal(stealer).is_some() {
+                        dropped += 1;
+                    }
+                }
+                self.shared.pending.fetch_sub(dropped, Ordering::SeqCst);
+            }
+        }
+        self.shared.unpark_all();
+        self.wait();
     }
-    pub fn is_empty(&self) -> bool {
-        self.head.is_none()
+    //Tasks that were queued or are running and have not finished yet
+    pub fn pending(&self) -> usize {
+        self.shared.pending.load(Ordering::SeqCst)
     }
-    pub fn is_full(&self) -> bool {
-        (self.head.is_some() && self.tail.is_some())
-            && ((self.tail.unwrap() + 1) % self.data.len() == self.head.unwrap())
+    pub fn enqueue(&self, task: ThreadFunc) {
+        self.shared.pending.fetch_add(1, Ordering::SeqCst);
+        self.shared.injector.push(task);
+        self.shared.unpark_one();
+    }
+    //Runs f on a worker, its output or error comes back through the handle
+    pub fn spawn<T, E, F>(&self, f: F) -> TaskHandle<T, E>
+    where
+        T: Send + 'static,
+        E: Send + 'static,
+        F: FnOnce(usize) -> Result<T, E> + Send + 'static,
+    {
+        let state = Arc::new(TaskState {
+            slot: Mutex::new(TaskSlot::Queued),
+            done: Condvar::new(),
+        });
+        let queued = QueuedTask(Arc::clone(&state));
+        self.enqueue(Box::new(move |t_id| {
+            if !queued.start() {
+                return Ok(());
+            }
+            match panic::catch_unwind(AssertUnwindSafe(|| f(t_id))) {
+                Ok(result) => queued.0.finish(result.map_err(TaskError::Failed)),
+                Err(payload) => {
+                    queued.0.finish(Err(TaskError::Panicked(
+                        TaskPanic::new(t_id, &*payload).message,
+                    )));
+                    //Still reported by the worker
+                    panic::resume_unwind(payload);
+                }
+            }
+            Ok(())
+        }));
+        TaskHandle { state }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-enum ThreadStatus {
-    Waiting,
-    Abort,
-    Working,
+#[derive(Debug)]
+pub enum TaskError<E = ThreadErr> {
+    Failed(E),
+    //Cancelled through the handle or dropped by an aborting shutdown before it ran
+    Cancelled,
+    Panicked(String),
+}
+impl<E: Display> Display for TaskError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskError::Failed(err) => write!(f, "task failed: {err}"),
+            TaskError::Cancelled => write!(f, "task was cancelled"),
+            TaskError::Panicked(message) => write!(f, "task panicked: {message}"),
+        }
+    }
 }
+impl<E: Debug + Display> Error for TaskError<E> {}
 
-pub type ThreadErr = Box<dyn 'static + Error + Send>;
-pub type ThreadFunc = Arc<dyn Fn(usize) -> Result<(), ThreadErr> + Send + Sync>;
+enum TaskSlot<T, E> {
+    Queued,
+    Running,
+    Finished(Result<T, TaskError<E>>),
+}
 
-pub struct ThreadPool<const S: usize> {
-    global_queue: Mutex<VecDeque<ThreadFunc>>,
-    local_queues: [Mutex<RingBuffer<ThreadFunc, S>>; S],
-    threads: Mutex<Option<[JoinHandle<()>; S]>>,
-    thread_status: [Mutex<ThreadStatus>; S],
-    thread_cond: Condvar,
+struct TaskState<T, E> {
+    slot: Mutex<TaskSlot<T, E>>,
+    done: Condvar,
 }
-impl<const S: usize> ThreadPool<S> {
-    pub fn new() -> Self {
-        Self {
-            global_queue: Mutex::new(VecDeque::new()),
-            local_queues: std::array::from_fn(|_| Mutex::new(RingBuffer::default())),
-            threads: Mutex::new(None),
-            thread_status: std::array::from_fn(|_| Mutex::new(ThreadStatus::Waiting)),
-            thread_cond: Condvar::new(),
-        }
-    }
-
-    pub fn dispatch(self: Arc<Self>) {
-        *self.threads.lock().unwrap() = Some(std::array::from_fn(|index| {
-            let ctxt = Arc::clone(&self);
-            std::thread::spawn(move || {
-                let id = index;
-                let mut timeout: u32 = 0;
-                let mut counter: u32 = 0;
-                let local = &ctxt.local_queues[id];
-                loop {
-                    let mut status = ctxt.thread_status[id].lock().unwrap();
-                    match *status {
-                        ThreadStatus::Waiting => {
-                            let mut t_stat = ctxt.thread_cond.wait(status).unwrap();
-                            *t_stat = match *t_stat {
-                                ThreadStatus::Abort => ThreadStatus::Abort,
-                                _ => ThreadStatus::Working,
-                            };
-                        }
-                        ThreadStatus::Working => {
-                            if counter % 61 == 0 {
-                                let mut queue = ctxt.global_queue.lock().unwrap();
-                                let mut lq = local.lock().unwrap();
-                                if !queue.is_empty() && !lq.is_full() {
-                                    let task = queue
-                                        .pop_front()
-                                        .expect("global queue should not be empty");
-                                    lq.enqueue(task).expect("local queue should not be full");
-                                }
-                            }
-                            counter += 1;
-                            {
-                                let mut lq = local.lock().unwrap();
-                                if !lq.is_empty() {
-                                    let task =
-                                        lq.dequeue().expect("Local queue should not be empty");
-                                    timeout = 0;
-                                    if let Err(err) = task(id) {
-                                        println!("Error executing task {err}");
-                                    };
-                                    continue;
-                                }
-                            }
-                            for t_id in 0..ctxt.local_queues.len() {
-                                if t_id == id {
-                                    continue;
-                                };
-                                if t_id < id {
-                                    let mut tq = ctxt.local_queues[t_id].lock().unwrap();
-                                    let mut local = local.lock().unwrap();
-                                    if !local.is_full() && !tq.is_empty() {
-                                        let task = tq
-                                            .steal()
-                                            .expect("foreign thread queue should not be empty");
-                                        local.enqueue(task).expect("should not be full");
-                                    }
-                                } else {
-                                    let mut local = local.lock().unwrap();
-                                    let mut tq = ctxt.local_queues[t_id].lock().unwrap();
-                                    if !local.is_full() && !tq.is_empty() {
-                                        let task = tq
-                                            .steal()
-                                            .expect("foreign thread queue should not be empty");
-                                        local.enqueue(task).expect("should not be full");
-                                    }
-                                }
-                            }
-                            timeout += 1;
-                            if timeout == 100 {
-                                *status = ThreadStatus::Waiting;
-                            }
-                        }
-                        ThreadStatus::Abort => {
-                            println!("Shutting Down");
-                            break;
+impl<T, E> TaskState<T, E> {
+    fn finish(&self, result: Result<T, TaskError<E>>) {
+        *lock(&self.slot) = TaskSlot::Finished(result);
+        self.done.notify_all();
+    }
+}
+
+//Marks the task cancelled if the pool drops it without running it
+struct QueuedTask<T, E>(Arc<TaskState<T, E>>);
+impl<T, E> QueuedTask<T, E> {
+    fn start(&self) -> bool {
+        let mut slot = lock(&self.0.slot);
+        if let TaskSlot::Queued = *slot {
+            *slot = TaskSlot::Running;
+            return true;
+        }
+        false
+    }
+}
+impl<T, E> Drop for QueuedTask<T, E> {
+    fn drop(&mut self) {
+        let queued = matches!(*lock(&self.0.slot), TaskSlot::Queued);
+        if queued {
+            self.0.finish(Err(TaskError::Cancelled));
+        }
+    }
+}
+
+pub struct TaskHandle<T, E = ThreadErr> {
+    state: Arc<TaskState<T, E>>,
+}
+impl<T, E> TaskHandle<T, E> {
+    //Blocks until the task has run or was cancelled
+    pub fn join(self) -> Result<T, TaskError<E>> {
+        let mut slot = lock(&self.state.slot);
+        loop {
+            match mem::replace(&mut *slot, TaskSlot::Running) {
+                TaskSlot::Finished(result) => return result,
+                unfinished => *slot = unfinished,
+            }
+            slot = self
+                .state
+                .done
+                .wait(slot)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+    //Gives the handle back while the task is still queued or running
+    pub fn try_join(self) -> Result<Result<T, TaskError<E>>, Self> {
+        if self.is_finished() {
+            Ok(self.join())
+        } else {
+            Err(self)
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        matches!(*lock(&self.state.slot), TaskSlot::Finished(_))
+    }
+    //Only a task that has not started can be cancelled, returns whether it was
+    pub fn cancel(&self) -> bool {
+        let mut slot = lock(&self.state.slot);
+        if let TaskSlot::Queued = *slot {
+            *slot = TaskSlot::Finished(Err(TaskError::Cancelled));
+            self.state.done.notify_all();
+            return true;
+        }
+        false
+    }
+}
+fn spawn_worker(ctxt: &Arc<Shared>, id: usize, local: Worker<ThreadFunc>) -> io::Result<()> {
+    let mut worker = WorkerThread {
+        ctxt: Arc::clone(ctxt),
+        id,
+        local: Some(local),
+    };
+    let handle = thread::Builder::new()
+        .name(format!("pool-worker-{id}"))
+        .spawn(move || worker.run())?;
+    lock(&ctxt.threads).push(handle);
+    Ok(())
+}
+
+//Owns a worker's local queue and hands it to a new thread if this one dies
+struct WorkerThread {
+    ctxt: Arc<Shared>,
+    id: usize,
+    local: Option<Worker<ThreadFunc>>,
+}
+impl WorkerThread {
+    fn run(&mut self) {
+        let ctxt = &self.ctxt;
+        let id = self.id;
+        let local = self.local.as_ref().expect("worker started without a queue");
+        let mut idle: u32 = 0;
+        *lock(&ctxt.parkers[id].thread) = Some(thread::current());
+        while !ctxt.aborting.load(Ordering::SeqCst) {
+            if let Some(task) = ctxt.find_task(id, local) {
+                idle = 0;
+                let result = panic::catch_unwind(AssertUnwindSafe(|| task(id)));
+                ctxt.pending.fetch_sub(1, Ordering::SeqCst);
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => println!("Error executing task {err}"),
+                    Err(payload) => {
+                        let err: ThreadErr = Box::new(TaskPanic::new(id, &*payload));
+                        match &ctxt.on_panic {
+                            Some(on_panic) => on_panic(id, err),
+                            None => println!("Error executing task {err}"),
                         }
                     }
                 }
-            })
-        }));
+                continue;
+            }
+            if ctxt.draining.load(Ordering::SeqCst) && ctxt.is_idle() {
+                break;
+            }
+            idle += 1;
+            if !ctxt.spin.backoff(idle) {
+                ctxt.park(id);
+                idle = 0;
+            }
+        }
+        println!("Shutting Down");
     }
+}
+impl Drop for WorkerThread {
+    fn drop(&mut self) {
+        if thread::panicking()
+            && !self.ctxt.aborting.load(Ordering::SeqCst)
+            && let Some(local) = self.local.take()
+            && let Err(err) = spawn_worker(&self.ctxt, self.id, local)
+        {
+            println!("Could not respawn worker {}: {err}", self.id);
+        }
+    }
+}
 
-    pub fn wait(&self) {
-        let threads = self.threads.lock().unwrap().take().unwrap();
-        for thread in threads {
-            thread.join().unwrap();
+fn steal(stealer: &Stealer<ThreadFunc>) -> Option<ThreadFunc> {
+    loop {
+        match stealer.steal() {
+            Steal::Success(task) => return Some(task),
+            Steal::Empty => return None,
+            Steal::Retry => continue,
         }
     }
-    pub fn enqueue(&self, task: ThreadFunc) {
-        let mut queue = self.global_queue.lock().unwrap();
-        queue.push_back(task);
-        self.thread_cond.notify_all();
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shutdown(ShutdownMode::Drain);
+    }
+}
+
+//S workers with S slots in every local queue
+pub struct FixedThreadPool<const S: usize>(ThreadPool);
+impl<const S: usize> Default for FixedThreadPool<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<const S: usize> FixedThreadPool<S> {
+    pub fn new() -> Self {
+        Self(ThreadPool::builder().workers(S).queue_capacity(S).build())
+    }
+    pub fn into_inner(self) -> ThreadPool {
+        self.0
+    }
+}
+impl<const S: usize> Deref for FixedThreadPool<S> {
+    type Target = ThreadPool;
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
 }
 
@@ -213,57 +590,15 @@ mod test {
 
     use super::*;
 
-    #[test]
-    fn ring_buffer_test() {
-        let mut ring_buff: RingBuffer<u32, 3> = RingBuffer::default();
-        ring_buff.enqueue(3).unwrap();
-        println!("DATA: {:?}", ring_buff.data);
-        ring_buff.enqueue(2).unwrap();
-        println!("DATA: {:?}", ring_buff.data);
-        ring_buff.enqueue(1).unwrap();
-        println!("DATA: {:?}", ring_buff.data);
-        assert!(ring_buff.is_full());
-        ring_buff.enqueue(1).expect_err("Did enqued past limit");
-        println!("DATA: {:?}", ring_buff.data);
-
-        assert_eq!(3, ring_buff.dequeue().unwrap());
-        println!("DATA: {:?}", ring_buff.data);
-        assert_eq!(2, ring_buff.dequeue().unwrap());
-        println!("DATA: {:?}", ring_buff.data);
-        assert_eq!(1, ring_buff.dequeue().unwrap());
-        println!("DATA: {:?}", ring_buff.data);
-        assert!(ring_buff.is_empty());
-
-        ring_buff.enqueue(1).unwrap();
-        assert_eq!(1, ring_buff.dequeue().unwrap());
-        println!("DATA: {:?}", ring_buff.data);
-        ring_buff.enqueue(2).unwrap();
-        assert_eq!(2, ring_buff.dequeue().unwrap());
-        println!("DATA: {:?}", ring_buff.data);
-        ring_buff.enqueue(3).unwrap();
-        assert_eq!(3, ring_buff.dequeue().unwrap());
-        println!("DATA: {:?}", ring_buff.data);
-
-        ring_buff.enqueue(2).unwrap();
-        println!("DATA: {:?}", ring_buff.data);
-        ring_buff.enqueue(3).unwrap();
-        println!("DATA: {:?}", ring_buff.data);
-        assert_eq!(3, ring_buff.steal().unwrap());
-        println!("DATA: {:?}", ring_buff.data);
-        assert_eq!(2, ring_buff.steal().unwrap());
-        println!("DATA: {:?}", ring_buff.data);
-    }
-
     #[test]
     fn thread_pool() {
         println!("Thread pool test");
-        let pool: Arc<ThreadPool<3>> = Arc::new(ThreadPool::new());
-        let dispatch = Arc::clone(&pool);
-        dispatch.dispatch();
+        let pool: Arc<FixedThreadPool<3>> = Arc::new(FixedThreadPool::new());
+        pool.dispatch();
 
         for i in 0..500 {
             println!("Queueing task");
-            pool.enqueue(Arc::new(move |_| {
+            pool.enqueue(Box::new(move |_| {
                 println!("Task {i}");
                 assert_eq!(i, i);
                 Ok(())
@@ -271,17 +606,189 @@ mod test {
         }
         println!("Started Queueing");
         sleep(Duration::from_secs(1));
-        assert!(pool.global_queue.lock().unwrap().is_empty());
-        for _ in 0..3 {
-            let status = Arc::clone(&pool);
-            println!("Queueing task");
-            pool.enqueue(Arc::new(move |id| {
-                let mut status = status.thread_status[id].lock().unwrap();
-                *status = ThreadStatus::Abort;
+        assert!(pool.shared.injector.is_empty());
+        pool.shutdown(ShutdownMode::Drain);
+        assert_eq!(pool.pending(), 0);
+    }
+
+    #[test]
+    fn owned_tasks() {
+        let pool = ThreadPool::builder().workers(2).build();
+        pool.dispatch();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for i in 0..10 {
+            //Neither needs to be Sync or cloned, the task owns them
+            let buffer = std::cell::RefCell::new(vec![i; 4]);
+            let sender = sender.clone();
+            pool.enqueue(Box::new(move |_| {
+                buffer.borrow_mut().push(i);
+                sender.send(buffer.into_inner()).unwrap();
+                Ok(())
+            }));
+        }
+        drop(sender);
+        pool.shutdown(ShutdownMode::Drain);
+        let mut buffers: Vec<Vec<i32>> = receiver.iter().collect();
+        buffers.sort();
+        assert_eq!(buffers.len(), 10);
+        assert_eq!(buffers[3], vec![3; 5]);
+    }
+
+    #[test]
+    fn shutdown_modes() {
+        let pool = ThreadPool::builder().workers(2).queue_capacity(4).build();
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..50 {
+            let ran = Arc::clone(&ran);
+            pool.enqueue(Box::new(move |_| {
+                ran.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }));
+        }
+        pool.dispatch();
+        pool.shutdown(ShutdownMode::Drain);
+        assert_eq!(ran.load(Ordering::SeqCst), 50, "Drain dropped tasks");
 
+        let pool = ThreadPool::builder().workers(2).queue_capacity(4).build();
+        for _ in 0..50 {
+            let ran = Arc::clone(&ran);
+            pool.enqueue(Box::new(move |_| {
+                ran.fetch_add(1, Ordering::SeqCst);
                 Ok(())
-            }))
+            }));
+        }
+        pool.shutdown(ShutdownMode::Abort);
+        pool.dispatch();
+        pool.wait();
+        assert_eq!(ran.load(Ordering::SeqCst), 50, "Abort ran queued tasks");
+        assert_eq!(pool.pending(), 0);
+
+        //Dropping a pool with sleeping workers has to join them
+        let pool = ThreadPool::builder().workers(2).queue_capacity(4).build();
+        pool.dispatch();
+        drop(pool);
+    }
+
+    #[test]
+    fn parking() {
+        let pool = ThreadPool::builder()
+            .workers(4)
+            .spin_policy(SpinPolicy::park_immediately())
+            .build();
+        pool.dispatch();
+        let parked = || pool.shared.parked.load(Ordering::SeqCst);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while parked() < 4 && std::time::Instant::now() < deadline {
+            sleep(Duration::from_millis(1));
+        }
+        assert_eq!(parked(), 4, "Idle workers did not park");
+
+        //One task only needs one worker
+        let (sender, receiver) = std::sync::mpsc::channel();
+        pool.enqueue(Box::new(move |t_id| {
+            sender.send(t_id).unwrap();
+            Ok(())
+        }));
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Parked worker was not woken");
+        assert!(parked() >= 3, "Every worker was woken for one task");
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..200 {
+            let ran = Arc::clone(&ran);
+            pool.enqueue(Box::new(move |_| {
+                ran.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }));
+        }
+        pool.shutdown(ShutdownMode::Drain);
+        assert_eq!(ran.load(Ordering::SeqCst), 200);
+    }
+
+    #[test]
+    fn task_handles() {
+        let pool = ThreadPool::builder().workers(2).build();
+        let cancelled = pool.spawn(|_| Ok::<_, ThreadErr>("never"));
+        assert!(cancelled.cancel());
+        assert!(!cancelled.cancel());
+        pool.dispatch();
+
+        let squares: Vec<TaskHandle<u64>> =
+            (0..20u64).map(|i| pool.spawn(move |_| Ok(i * i))).collect();
+        let failed = pool.spawn(|_| -> Result<(), String> { Err("bad input".to_string()) });
+        let mut slow = pool.spawn(|_| -> Result<u8, ThreadErr> {
+            sleep(Duration::from_millis(50));
+            Ok(7)
+        });
+
+        let total: u64 = squares.into_iter().map(|task| task.join().unwrap()).sum();
+        assert_eq!(total, (0..20u64).map(|i| i * i).sum());
+        match failed.join() {
+            Err(TaskError::Failed(err)) => assert_eq!(err, "bad input"),
+            other => panic!("Expected the task error, got {other:?}"),
+        }
+        assert!(matches!(cancelled.join(), Err(TaskError::Cancelled)));
+        let value = loop {
+            match slow.try_join() {
+                Ok(result) => break result.unwrap(),
+                Err(handle) => slow = handle,
+            }
+            sleep(Duration::from_millis(5));
+        };
+        assert_eq!(value, 7);
+        pool.shutdown(ShutdownMode::Drain);
+
+        //Tasks dropped by an aborting shutdown report that they never ran
+        let pool = ThreadPool::builder().workers(1).build();
+        let dropped = pool.spawn(|_| Ok::<_, ThreadErr>(()));
+        pool.shutdown(ShutdownMode::Abort);
+        assert!(matches!(dropped.join(), Err(TaskError::Cancelled)));
+    }
+
+    #[test]
+    fn panic_isolation() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = Mutex::new(sender);
+        let first = AtomicBool::new(true);
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .on_panic(move |t_id, err| {
+                sender
+                    .lock()
+                    .unwrap()
+                    .send(format!("{t_id} {err}"))
+                    .unwrap();
+                //Takes the worker down with it, the pool has to replace it
+                if first.swap(false, Ordering::SeqCst) {
+                    panic!("Callback failed");
+                }
+            })
+            .build();
+        pool.dispatch();
+
+        pool.enqueue(Box::new(|_| panic!("Task failed")));
+        let handle = pool.spawn(|_| -> Result<(), ThreadErr> { panic!("Spawned task failed") });
+        match handle.join() {
+            Err(TaskError::Panicked(message)) => assert_eq!(message, "Spawned task failed"),
+            other => panic!("Expected a panic, got {other:?}"),
+        }
+        let reports: Vec<String> = receiver.iter().take(2).collect();
+        println!("Reports {reports:?}");
+        assert!(reports[0].contains("Task failed"));
+        assert!(reports[1].contains("Spawned task failed"));
+
+        //The single worker died once and was respawned
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..20 {
+            let ran = Arc::clone(&ran);
+            pool.enqueue(Box::new(move |_| {
+                ran.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }));
         }
-        //pool.wait();
+        pool.shutdown(ShutdownMode::Drain);
+        assert_eq!(ran.load(Ordering::SeqCst), 20);
+        assert_eq!(pool.pending(), 0);
     }
 }
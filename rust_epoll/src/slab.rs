@@ -0,0 +1,189 @@
+use std::mem;
+
+const NO_FREE: usize = usize::MAX;
+
+#[derive(Debug)]
+enum Entry<T> {
+    Occupied(u32, T),
+    //Next free index, the free slots form a linked list through the entries
+    Vacant(u32, usize),
+}
+
+//Stable indices with O(1) insert and remove. Each slot counts how often it has been freed,
+//so callers can tell a reused index from the one they were handed before
+#[derive(Debug)]
+pub struct Slab<T> {
+    entries: Vec<Entry<T>>,
+    next_free: usize,
+    len: usize,
+    //Generation for slots created after a shrink, above anything the dropped slots handed out
+    floor: u32,
+    //Free slots at the end are dropped once len falls below this
+    shrink_below: usize,
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T> Slab<T> {
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: Vec::with_capacity(capacity),
+            next_free: NO_FREE,
+            len: 0,
+            floor: 0,
+            shrink_below: 0,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    //Slots in use or free, this is what shrinking gives back
+    pub fn slots(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn insert(&mut self, value: T) -> usize {
+        self.len += 1;
+        if self.next_free == NO_FREE {
+            self.entries.push(Entry::Occupied(self.floor, value));
+            self.shrink_below = self.entries.len() / 4;
+            return self.entries.len() - 1;
+        }
+        let index = self.next_free;
+        let Entry::Vacant(generation, next) = self.entries[index] else {
+            unreachable!("free list points at an occupied slot");
+        };
+        self.next_free = next;
+        self.entries[index] = Entry::Occupied(generation, value);
+        index
+    }
+    pub fn generation(&self, index: usize) -> Option<u32> {
+        match self.entries.get(index)? {
+            Entry::Occupied(generation, _) | Entry::Vacant(generation, _) => Some(*generation),
+        }
+    }
+    pub fn get(&self, index: usize) -> Option<&T> {
+        match self.entries.get(index)? {
+            Entry::Occupied(_, value) => Some(value),
+            Entry::Vacant(..) => None,
+        }
+    }
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        match self.entries.get_mut(index)? {
+            Entry::Occupied(_, value) => Some(value),
+            Entry::Vacant(..) => None,
+        }
+    }
+    pub fn contains(&self, index: usize) -> bool {
+        self.get(index).is_some()
+    }
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        let Some(Entry::Occupied(generation, _)) = self.entries.get(index) else {
+            return None;
+        };
+        let vacant = Entry::Vacant(generation.wrapping_add(1), self.next_free);
+        let Entry::Occupied(_, value) = mem::replace(&mut self.entries[index], vacant) else {
+            unreachable!();
+        };
+        self.next_free = index;
+        self.len -= 1;
+        if self.len < self.shrink_below {
+            self.shrink();
+            //Each attempt walks the whole slab, so wait for len to halve before the next one
+            self.shrink_below = self.len / 2;
+        }
+        Some(value)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| match entry {
+                Entry::Occupied(_, value) => Some((index, value)),
+                Entry::Vacant(..) => None,
+            })
+    }
+    //Indices of occupied slots never change, so only the free slots after the last one can go
+    fn shrink(&mut self) {
+        let trailing = self
+            .entries
+            .iter()
+            .rev()
+            .take_while(|entry| matches!(entry, Entry::Vacant(..)))
+            .count();
+        if trailing == 0 {
+            return;
+        }
+        let keep = self.entries.len() - trailing;
+        for entry in self.entries.drain(keep..) {
+            if let Entry::Vacant(generation, _) = entry
+                && generation.wrapping_sub(self.floor) < u32::MAX / 2
+            {
+                self.floor = generation;
+            }
+        }
+        self.next_free = NO_FREE;
+        for (index, entry) in self.entries.iter_mut().enumerate().rev() {
+            if let Entry::Vacant(_, next) = entry {
+                *next = self.next_free;
+                self.next_free = index;
+            }
+        }
+        self.entries
+            .shrink_to(keep.max(self.entries.capacity() / 2));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slab_test() {
+        let mut slab: Slab<&str> = Slab::new();
+        let a = slab.insert("a");
+        let b = slab.insert("b");
+        let c = slab.insert("c");
+        assert_eq!((a, b, c), (0, 1, 2));
+        assert_eq!(slab.remove(b), Some("b"));
+        assert_eq!(slab.remove(b), None);
+        assert_eq!(slab.generation(b), Some(1));
+
+        //Freed slots are reused before the slab grows
+        let d = slab.insert("d");
+        assert_eq!(d, b);
+        assert_eq!(slab.get(d), Some(&"d"));
+        assert_eq!(slab.generation(d), Some(1));
+        assert_eq!(slab.len(), 3);
+        let items: Vec<(usize, &&str)> = slab.iter().collect();
+        println!("Items {items:?}");
+        assert_eq!(items, vec![(0, &"a"), (1, &"d"), (2, &"c")]);
+    }
+
+    #[test]
+    fn shrink_test() {
+        let mut slab: Slab<usize> = Slab::new();
+        let keys: Vec<usize> = (0..100).map(|i| slab.insert(i)).collect();
+        for key in keys.iter().skip(10).rev() {
+            slab.remove(*key);
+        }
+        println!("Slots after removing the tail {}", slab.slots());
+        assert!(slab.slots() <= 25, "Slab did not shrink");
+        assert_eq!(slab.len(), 10);
+
+        //Slots created after a shrink never repeat a generation the old ones handed out
+        while slab.slots() <= keys[50] {
+            slab.insert(0);
+        }
+        println!("Generation of slot 50 {:?}", slab.generation(keys[50]));
+        assert_ne!(slab.generation(keys[50]), Some(0));
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    fmt::{self, Display},
+    io::{Error, ErrorKind, Read, Write},
+    mem,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::{
+        fd::{AsRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr as UnixSocketAddr, UnixListener, UnixStream},
+    },
+};
+
+//A connected socket, TCP or Unix domain
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+impl Stream {
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+    //Process that connected a Unix socket, as it was when it called connect
+    pub fn peer_cred(&self) -> Result<PeerCred, Error> {
+        let Stream::Unix(stream) = self else {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "peer credentials are only available on Unix sockets",
+            ));
+        };
+        let mut cred: libc::ucred = unsafe { mem::zeroed() };
+        let mut len = libc::socklen_t::try_from(mem::size_of::<libc::ucred>()).unwrap();
+        let err = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if err == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(PeerCred {
+            pid: cred.pid,
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+}
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+//Where a connection came from
+#[derive(Debug, Clone)]
+pub enum Addr {
+    Inet(SocketAddr),
+    Unix(UnixSocketAddr),
+}
+impl Addr {
+    pub fn inet(&self) -> Option<SocketAddr> {
+        match self {
+            Addr::Inet(addr) => Some(*addr),
+            Addr::Unix(_) => None,
+        }
+    }
+}
+impl Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Inet(addr) => write!(f, "{addr}"),
+            Addr::Unix(addr) => {
+                if let Some(path) = addr.as_pathname() {
+                    write!(f, "{}", path.display())
+                } else if let Some(name) = addr.as_abstract_name() {
+                    write!(f, "@{}", String::from_utf8_lossy(name))
+                } else {
+                    write!(f, "(unnamed)")
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ListenSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+impl ListenSocket {
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+        match self {
+            ListenSocket::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            ListenSocket::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+}
+impl AsRawFd for ListenSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            ListenSocket::Tcp(listener) => listener.as_raw_fd(),
+            ListenSocket::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+impl From<TcpListener> for ListenSocket {
+    fn from(listener: TcpListener) -> Self {
+        ListenSocket::Tcp(listener)
+    }
+}
+impl From<UnixListener> for ListenSocket {
+    fn from(listener: UnixListener) -> Self {
+        ListenSocket::Unix(listener)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn peer_cred_test() {
+        let name = format!("rust_epoll_cred_{}", std::process::id());
+        let addr = UnixSocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let listener = UnixListener::bind_addr(&addr).unwrap();
+        let _client = UnixStream::connect_addr(&addr).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+
+        let cred = Stream::Unix(accepted).peer_cred().unwrap();
+        println!("Peer {cred:?}");
+        assert_eq!(cred.pid, i32::try_from(std::process::id()).unwrap());
+        assert_eq!(cred.uid, unsafe { libc::getuid() });
+        let local = Addr::Unix(listener.local_addr().unwrap());
+        assert_eq!(local.to_string(), format!("@{name}"));
+    }
+}
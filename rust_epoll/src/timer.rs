@@ -0,0 +1,178 @@
+use std::{
+    io::{Error, ErrorKind},
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr::null_mut,
+    time::{Duration, Instant},
+};
+
+//Hashed timer wheel, each slot holds the timers whose tick maps to it and
+//entries further out than one turn wait in their slot until their tick comes round
+#[derive(Debug)]
+pub struct TimerWheel<K> {
+    slots: Vec<Vec<(K, u64)>>,
+    tick: Duration,
+    start: Instant,
+    //Ticks that have been fully processed
+    current: u64,
+    len: usize,
+}
+impl<K> TimerWheel<K> {
+    pub fn new(tick: Duration, slots: usize) -> Self {
+        Self {
+            slots: (0..slots.max(1)).map(|_| Vec::new()).collect(),
+            tick: tick.max(Duration::from_millis(1)),
+            start: Instant::now(),
+            current: 0,
+            len: 0,
+        }
+    }
+    pub fn tick(&self) -> Duration {
+        self.tick
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    //Fires on the first advance at or after the deadline, rounded up to the next tick
+    pub fn schedule(&mut self, key: K, deadline: Instant) {
+        let elapsed = deadline.saturating_duration_since(self.start);
+        let tick = u64::try_from(elapsed.as_nanos().div_ceil(self.tick.as_nanos()))
+            .unwrap_or(u64::MAX)
+            .max(self.current + 1);
+        let slot = self.slot(tick);
+        self.slots[slot].push((key, tick));
+        self.len += 1;
+    }
+    //Hands every timer that is due by now to expired
+    pub fn advance<F>(&mut self, now: Instant, mut expired: F)
+    where
+        F: FnMut(K),
+    {
+        let now_tick = u64::try_from(
+            now.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos(),
+        )
+        .unwrap_or(u64::MAX);
+        if now_tick <= self.current {
+            return;
+        }
+        //After a full turn every slot has been visited once
+        let turns = (now_tick - self.current).min(self.slots.len() as u64);
+        for tick in self.current + 1..=self.current + turns {
+            let slot = self.slot(tick);
+            let entries = mem::take(&mut self.slots[slot]);
+            for (key, deadline) in entries {
+                if deadline <= now_tick {
+                    self.len -= 1;
+                    expired(key);
+                } else {
+                    self.slots[slot].push((key, deadline));
+                }
+            }
+        }
+        self.current = now_tick;
+    }
+    fn slot(&self, tick: u64) -> usize {
+        usize::try_from(tick % self.slots.len() as u64).unwrap()
+    }
+}
+
+//Monotonic timerfd, readable once per expiry
+#[derive(Debug)]
+pub(crate) struct TimerFd {
+    fd: OwnedFd,
+}
+impl TimerFd {
+    pub(crate) fn new() -> Result<Self, Error> {
+        unsafe {
+            let fd = libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            );
+            if fd == -1 {
+                return Err(Error::last_os_error());
+            }
+            Ok(Self {
+                fd: OwnedFd::from_raw_fd(fd),
+            })
+        }
+    }
+    //Zero for either disarms that part, a zero first expiry disarms the timer
+    pub(crate) fn set(&self, first: Duration, interval: Duration) -> Result<(), Error> {
+        let spec = libc::itimerspec {
+            it_interval: timespec(interval),
+            it_value: timespec(first),
+        };
+        unsafe {
+            if libc::timerfd_settime(self.fd.as_raw_fd(), 0, &spec, null_mut()) == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+    //Expirations since the last read, zero if the timer has not fired
+    pub(crate) fn drain(&self) -> u64 {
+        let mut expirations: u64 = 0;
+        let read = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                &mut expirations as *mut u64 as *mut libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+        if read == -1 && Error::last_os_error().kind() != ErrorKind::WouldBlock {
+            return 0;
+        }
+        expirations
+    }
+}
+impl AsRawFd for TimerFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+fn timespec(duration: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: libc::time_t::try_from(duration.as_secs()).unwrap_or(libc::time_t::MAX),
+        tv_nsec: libc::c_long::from(duration.subsec_nanos()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timer_wheel_test() {
+        let mut wheel: TimerWheel<u32> = TimerWheel::new(Duration::from_millis(10), 8);
+        let start = wheel.start;
+        wheel.schedule(1, start + Duration::from_millis(25));
+        wheel.schedule(2, start + Duration::from_millis(50));
+        //Further out than one turn of the wheel
+        wheel.schedule(3, start + Duration::from_millis(200));
+        assert_eq!(wheel.len(), 3);
+
+        let mut fired = Vec::new();
+        wheel.advance(start + Duration::from_millis(20), |key| fired.push(key));
+        assert!(fired.is_empty(), "Timer fired early {fired:?}");
+        wheel.advance(start + Duration::from_millis(60), |key| fired.push(key));
+        assert_eq!(fired, vec![1, 2]);
+        wheel.advance(start + Duration::from_millis(150), |key| fired.push(key));
+        assert_eq!(fired, vec![1, 2]);
+        wheel.advance(start + Duration::from_millis(500), |key| fired.push(key));
+        assert_eq!(fired, vec![1, 2, 3]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn timerfd_test() {
+        let timer = TimerFd::new().unwrap();
+        assert_eq!(timer.drain(), 0);
+        timer.set(Duration::from_millis(5), Duration::ZERO).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(timer.drain(), 1);
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    io::{Error, ErrorKind},
+    mem,
+    net::{SocketAddr, UdpSocket},
+    os::fd::{AsRawFd, RawFd},
+    ptr::null_mut,
+};
+
+use crate::polller::socket_addr_from;
+
+//Datagrams taken per recvmmsg call
+const BATCH: usize = 16;
+//Largest UDP payload over IPv4, longer IPv6 jumbograms are truncated
+const MAX_DATAGRAM: usize = 65507;
+
+//Buffers reused by every receive, allocated the first time a UDP socket is added
+pub(crate) struct RecvBatch {
+    buffers: Vec<Vec<u8>>,
+    addrs: Vec<libc::sockaddr_storage>,
+}
+impl RecvBatch {
+    pub(crate) fn new() -> Self {
+        Self {
+            buffers: (0..BATCH).map(|_| vec![0; MAX_DATAGRAM]).collect(),
+            addrs: vec![unsafe { mem::zeroed() }; BATCH],
+        }
+    }
+    //Receives until the socket would block, handing each datagram to received
+    pub(crate) fn drain<F>(&mut self, fd: RawFd, mut received: F) -> Result<usize, Error>
+    where
+        F: FnMut(SocketAddr, &[u8]),
+    {
+        let mut total = 0;
+        loop {
+            //Rebuilt every call so the poller holds no raw pointers and stays Send
+            let mut iovecs: Vec<libc::iovec> = self
+                .buffers
+                .iter_mut()
+                .map(|buffer| libc::iovec {
+                    iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: buffer.len(),
+                })
+                .collect();
+            let mut headers: Vec<libc::mmsghdr> = iovecs
+                .iter_mut()
+                .zip(self.addrs.iter_mut())
+                .map(|(iovec, addr)| {
+                    let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                    header.msg_hdr.msg_name =
+                        addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                    header.msg_hdr.msg_namelen =
+                        libc::socklen_t::try_from(mem::size_of::<libc::sockaddr_storage>())
+                            .unwrap();
+                    header.msg_hdr.msg_iov = iovec;
+                    header.msg_hdr.msg_iovlen = 1;
+                    header
+                })
+                .collect();
+            let count = unsafe {
+                libc::recvmmsg(
+                    fd,
+                    headers.as_mut_ptr(),
+                    u32::try_from(headers.len()).unwrap(),
+                    libc::MSG_DONTWAIT,
+                    null_mut(),
+                )
+            };
+            if count == -1 {
+                let err = Error::last_os_error();
+                match err.kind() {
+                    ErrorKind::WouldBlock => return Ok(total),
+                    ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
+            }
+            let count = usize::try_from(count).unwrap();
+            for (index, header) in headers.iter().take(count).enumerate() {
+                let len = usize::try_from(header.msg_len).unwrap();
+                match socket_addr_from(&self.addrs[index]) {
+                    Ok(from) => received(from, &self.buffers[index][..len]),
+                    Err(err) => println!("Dropped datagram: {err}"),
+                }
+            }
+            total += count;
+            if count < BATCH {
+                return Ok(total);
+            }
+        }
+    }
+}
+
+fn sockaddr_from(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let raw = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            raw.sin_family = libc::sa_family_t::try_from(libc::AF_INET).unwrap();
+            raw.sin_port = addr.port().to_be();
+            raw.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let raw = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            raw.sin6_family = libc::sa_family_t::try_from(libc::AF_INET6).unwrap();
+            raw.sin6_port = addr.port().to_be();
+            raw.sin6_flowinfo = addr.flowinfo();
+            raw.sin6_addr.s6_addr = addr.ip().octets();
+            raw.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, libc::socklen_t::try_from(len).unwrap())
+}
+
+//Sends with as few sendmmsg calls as it can, returns how many went out before the socket
+//would block. Safe to call from any thread
+pub fn send_batch(socket: &UdpSocket, datagrams: &[(SocketAddr, &[u8])]) -> Result<usize, Error> {
+    let mut sent = 0;
+    while sent < datagrams.len() {
+        let batch = &datagrams[sent..datagrams.len().min(sent + BATCH)];
+        let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> =
+            batch.iter().map(|(addr, _)| sockaddr_from(addr)).collect();
+        let mut iovecs: Vec<libc::iovec> = batch
+            .iter()
+            .map(|(_, bytes)| libc::iovec {
+                iov_base: bytes.as_ptr() as *mut libc::c_void,
+                iov_len: bytes.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iovec, (addr, len))| {
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                header.msg_hdr.msg_namelen = *len;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect();
+        let count = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                u32::try_from(headers.len()).unwrap(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if count == -1 {
+            let err = Error::last_os_error();
+            match err.kind() {
+                ErrorKind::WouldBlock => return Ok(sent),
+                ErrorKind::Interrupted => continue,
+                _ => return Err(err),
+            }
+        }
+        sent += usize::try_from(count).unwrap();
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn batch_test() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = receiver.local_addr().unwrap();
+
+        let payloads: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i; usize::from(i) + 1]).collect();
+        let datagrams: Vec<(SocketAddr, &[u8])> = payloads
+            .iter()
+            .map(|bytes| (to, bytes.as_slice()))
+            .collect();
+        let sent = send_batch(&sender, &datagrams).unwrap();
+        assert_eq!(sent, 40);
+
+        let mut batch = RecvBatch::new();
+        let mut received = Vec::new();
+        let total = batch
+            .drain(receiver.as_raw_fd(), |from, bytes| {
+                assert_eq!(from, sender.local_addr().unwrap());
+                received.push(bytes.to_vec());
+            })
+            .unwrap();
+        println!("Received {total} datagrams");
+        assert_eq!(received, payloads);
+    }
+}
@@ -1,84 +1,65 @@
 use std::{
-    process,
     sync::Mutex,
     time::{Duration, Instant},
 };
 
+use crate::slab::Slab;
+
 pub struct Telementry {
-    processing: Mutex<Vec<Option<Instant>>>,
-    finished: Mutex<Vec<Option<Duration>>>,
+    processing: Mutex<Slab<Instant>>,
+    finished: Mutex<Vec<Duration>>,
 }
 
 impl Default for Telementry {
     fn default() -> Self {
         Self {
-            processing: Mutex::new(Vec::new()),
+            processing: Mutex::new(Slab::new()),
             finished: Mutex::new(Vec::new()),
         }
     }
 }
 impl Telementry {
     pub fn watch_connection(&mut self) -> usize {
-        let mut processing_list = self.processing.lock().unwrap();
-        if let Some(id) = processing_list
-            .iter_mut()
-            .position(|timer_slot| timer_slot.is_none())
-        {
-            processing_list[id] = Some(Instant::now());
-            id
-        } else {
-            processing_list.push(Some(Instant::now()));
-            processing_list.len()
-        }
+        self.processing.lock().unwrap().insert(Instant::now())
     }
     pub fn stop_watching_connection(&mut self, id: usize) {
-        let mut processing_list = self.processing.lock().unwrap();
-
-        let timer = processing_list.get_mut(id);
-        if timer.is_none() {
+        let Some(timer) = self.processing.lock().unwrap().remove(id) else {
             println!("Lost Connection with id {id}");
             return;
-        }
-
-        let timer = timer.unwrap().take().unwrap();
-
-        let mut finished_list = self.finished.lock().unwrap();
-
-        if finished_list.len() > id {
-            finished_list[id] = Some(timer.elapsed());
-        } else {
-            finished_list.push(Some(timer.elapsed()));
-        }
+        };
+        //Finished timings are drained by get_data
+        self.finished.lock().unwrap().push(timer.elapsed());
     }
     pub fn get_data(&mut self) -> (u64, u64, f64, f64, f64) {
+        let data = self.snapshot();
+        self.finished.lock().unwrap().clear();
+        data
+    }
+    //Same numbers as get_data but leaves the finished timings in place, so readers like a
+    //stats endpoint do not take them from the next get_data call
+    pub fn snapshot(&self) -> (u64, u64, f64, f64, f64) {
         let mut connections: u64 = 0;
         let mut finished_connections: u64 = 0;
         let mut total_lantency: u64 = 0;
 
-        let mut finished_list = self.finished.lock().unwrap();
+        let finished_list = self.finished.lock().unwrap();
         let processing_list = self.processing.lock().unwrap();
 
-        for timer in processing_list.iter() {
-            if timer.is_some() {
-                connections += 1;
-            }
-        }
+        connections += u64::try_from(processing_list.len()).unwrap();
 
         let mut min_duration: u64 = 0;
         let mut max_duration: u64 = 0;
-        for (index, duration) in finished_list.iter_mut().enumerate() {
-            if let Some(duration) = duration.take() {
-                let duration = duration.as_secs();
-                if index == 0 || duration < min_duration {
-                    min_duration = duration;
-                }
-                if duration > max_duration {
-                    max_duration = duration;
-                }
-                connections += 1;
-                total_lantency += duration;
-                finished_connections += 1
+        for (index, duration) in finished_list.iter().enumerate() {
+            let duration = duration.as_secs();
+            if index == 0 || duration < min_duration {
+                min_duration = duration;
+            }
+            if duration > max_duration {
+                max_duration = duration;
             }
+            connections += 1;
+            total_lantency += duration;
+            finished_connections += 1
         }
         let avrg_latency: f64 = if finished_connections != 0 {
             total_lantency as f64 / finished_connections as f64
@@ -93,4 +74,10 @@ impl Telementry {
             max_duration as f64,
         )
     }
+    pub fn to_json(&self) -> String {
+        let (connections, finished, average, min, max) = self.snapshot();
+        format!(
+            "{{\"connections\":{connections},\"finished\":{finished},\"average\":{average},\"min\":{min},\"max\":{max}}}"
+        )
+    }
 }